@@ -0,0 +1,167 @@
+// Discovery and checksum editing over a synthetic vendor tree, the two passes that go over
+// every vendored crate. Run with `cargo bench`.
+
+use cargo_atomic_patch::{
+    checksum::{remove_cargo_toml_checksum, ChecksumMode},
+    manifest::ManifestInfo,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use std::path::{Path, PathBuf};
+
+const CRATES: usize = 500;
+// Files listed in each checksum file
+const FILES: usize = 100;
+const CHECKSUM_FILE: &str = ".cargo-checksum.json";
+
+// A vendor directory with CRATES crates, each with a manifest shaped like the ones cargo
+// normalizes on publish and a checksum file. Every fourth crate has a build script.
+fn vendor_tree() -> (PathBuf, Vec<PathBuf>) {
+    let dir = std::env::temp_dir().join(format!("atomic-patch-bench-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut manifests = Vec::new();
+    for i in 0..CRATES {
+        let root = dir.join(format!("crate-{i}"));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        let manifest = root.join("Cargo.toml");
+        std::fs::write(&manifest, manifest_contents(i)).unwrap();
+        if i.is_multiple_of(4) {
+            std::fs::write(root.join("build.rs"), "fn main() {}\n").unwrap();
+        }
+        std::fs::write(root.join(CHECKSUM_FILE), checksum_contents()).unwrap();
+        manifests.push(manifest);
+    }
+    (dir, manifests)
+}
+
+fn manifest_contents(i: usize) -> String {
+    let mut contents = format!(
+        r#"# THIS FILE IS AUTOMATICALLY GENERATED BY CARGO
+
+[package]
+edition = "2021"
+rust-version = "1.60"
+name = "crate-{i}"
+version = "1.{i}.0"
+authors = ["Someone <someone@example.com>"]
+build = {build}
+autolib = false
+description = "A crate of the synthetic vendor tree"
+readme = "README.md"
+keywords = ["synthetic", "bench"]
+categories = ["no-std"]
+license = "MIT OR Apache-2.0"
+repository = "https://example.com/crate-{i}"
+
+[features]
+default = ["std"]
+std = []
+alloc = []
+
+[lib]
+name = "crate_{i}"
+path = "src/lib.rs"
+"#,
+        build = if i.is_multiple_of(4) {
+            "\"build.rs\""
+        } else {
+            "false"
+        },
+    );
+    for dep in 0..8 {
+        contents.push_str(&format!(
+            "\n[dependencies.dep-{dep}]\nversion = \"0.{dep}\"\noptional = true\ndefault-features = false\n"
+        ));
+    }
+    contents
+}
+
+fn checksum_contents() -> String {
+    let files = (0..FILES)
+        .map(|f| format!("\"src/file-{f}.rs\":\"{:064x}\"", f))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{\"files\":{{{files}}},\"package\":\"{:064x}\"}}", 42)
+}
+
+// Discovery before ManifestInfo: a separate read and parse of the manifest for each thing it
+// needs to know
+fn separate_parses(manifest: &Path) -> usize {
+    let parse = || -> Option<toml::Table> { std::fs::read_to_string(manifest).ok()?.parse().ok() };
+    let has_package = parse().is_some_and(|m| m.contains_key("package"));
+    let id = parse().and_then(|m| {
+        let package = m.get("package")?;
+        Some((
+            package.get("name")?.as_str()?.to_string(),
+            package.get("version")?.as_str()?.to_string(),
+        ))
+    });
+    let links = parse().and_then(|m| m.get("package")?.get("links")?.as_str().map(String::from));
+    let build = parse().and_then(|m| m.get("package")?.get("build").cloned());
+    has_package as usize
+        + id.is_some() as usize
+        + links.is_some() as usize
+        + build.is_some() as usize
+}
+
+fn discovery(c: &mut Criterion) {
+    let (dir, manifests) = vendor_tree();
+    let mut group = c.benchmark_group("discovery");
+    group.bench_function("separate-parses", |b| {
+        b.iter(|| manifests.iter().map(|m| separate_parses(m)).sum::<usize>())
+    });
+    group.bench_function("manifest-info", |b| {
+        b.iter(|| {
+            manifests
+                .iter()
+                .filter_map(|m| ManifestInfo::read(m))
+                .count()
+        })
+    });
+    group.finish();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+fn parsing(c: &mut Criterion) {
+    let contents = manifest_contents(0);
+    let dir = Path::new("/vendor/crate-0");
+    c.bench_function("parse-manifest", |b| {
+        b.iter(|| ManifestInfo::parse(&contents, dir))
+    });
+}
+
+fn checksums(c: &mut Criterion) {
+    let (dir, manifests) = vendor_tree();
+    let roots = manifests
+        .iter()
+        .map(|m| m.parent().unwrap().to_path_buf())
+        .collect::<Vec<_>>();
+    let original = checksum_contents();
+    let modified = [PathBuf::from("Cargo.toml"), PathBuf::from("src/file-7.rs")];
+    let restore = || {
+        for root in &roots {
+            std::fs::write(root.join(CHECKSUM_FILE), &original).unwrap();
+        }
+    };
+    let mut group = c.benchmark_group("checksums");
+    for (name, mode) in [
+        ("clear", ChecksumMode::Clear),
+        ("modified", ChecksumMode::Modified),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                restore,
+                |()| {
+                    for root in &roots {
+                        remove_cargo_toml_checksum(root, CHECKSUM_FILE, mode, &modified).unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+criterion_group!(benches, discovery, parsing, checksums);
+criterion_main!(benches);
@@ -0,0 +1,286 @@
+use anyhow::Result;
+use std::collections::{HashMap, VecDeque};
+use tracing::warn;
+
+use crate::metadata::{Metadata, Package};
+
+// Whether the library of a package links std, given the features it is built with.
+// This is a heuristic: a crate is considered no_std if the root of its library has a
+// `#![no_std]` attribute, or a `#![cfg_attr(..., no_std)]` whose predicate holds.
+// Proc macros run on the host and never count.
+pub fn links_std(package: &Package, features: &[String]) -> bool {
+    if package.is_proc_macro() {
+        return false;
+    }
+    let Some(lib) = package.lib() else {
+        return false;
+    };
+    let Ok(source) = std::fs::read_to_string(&lib.src_path) else {
+        return false;
+    };
+    !is_no_std(&source, features)
+}
+
+// Whether the source of a crate root declares the crate no_std when built with `features`
+pub fn is_no_std(source: &str, features: &[String]) -> bool {
+    inner_attributes(source)
+        .into_iter()
+        .any(|attr| match attr.as_str() {
+            "no_std" => true,
+            attr => attr
+                .strip_prefix("cfg_attr(")
+                .and_then(|a| a.strip_suffix(')'))
+                .is_some_and(|a| {
+                    let mut parts = split_predicates(a);
+                    let predicate = parts.next().unwrap_or_default();
+                    parts.any(|a| a == "no_std") && eval_cfg(predicate, features)
+                }),
+        })
+}
+
+// Every `#![...]` attribute in the source, with whitespace removed
+fn inner_attributes(source: &str) -> Vec<String> {
+    let source = strip_comments(source);
+    source
+        .match_indices("#![")
+        .filter_map(|(start, _)| {
+            let body = &source[start + 3..];
+            let mut depth = 1;
+            let end = body.find(|c| {
+                match c {
+                    '[' => depth += 1,
+                    ']' => depth -= 1,
+                    _ => {}
+                }
+                depth == 0
+            })?;
+            Some(body[..end].chars().filter(|c| !c.is_whitespace()).collect())
+        })
+        .collect()
+}
+
+// The source without its line and (possibly nested) block comments, so that commented out
+// attributes are not taken for real ones. String literals are copied as they are, since they
+// may contain `//` themselves.
+fn strip_comments(source: &str) -> String {
+    let mut stripped = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('/', Some('/')) => while chars.next_if(|c| *c != '\n').is_some() {},
+            ('/', Some('*')) => {
+                chars.next();
+                let mut depth = 1;
+                while depth > 0 {
+                    match (chars.next(), chars.peek()) {
+                        (Some('/'), Some('*')) => {
+                            chars.next();
+                            depth += 1;
+                        }
+                        (Some('*'), Some('/')) => {
+                            chars.next();
+                            depth -= 1;
+                        }
+                        (Some(_), _) => {}
+                        (None, _) => break,
+                    }
+                }
+                stripped.push(' ');
+            }
+            ('"', _) => {
+                stripped.push(c);
+                while let Some(c) = chars.next() {
+                    stripped.push(c);
+                    match c {
+                        '\\' => stripped.extend(chars.next()),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            _ => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+// Evaluate a cfg predicate (without whitespace) for a target build with the given features.
+// Only features are known, any other configuration option is assumed to be unset.
+fn eval_cfg(predicate: &str, features: &[String]) -> bool {
+    if let Some(inner) = predicate
+        .strip_prefix("not(")
+        .and_then(|p| p.strip_suffix(')'))
+    {
+        return !eval_cfg(inner, features);
+    }
+    if let Some(inner) = predicate
+        .strip_prefix("all(")
+        .and_then(|p| p.strip_suffix(')'))
+    {
+        return split_predicates(inner).all(|p| eval_cfg(p, features));
+    }
+    if let Some(inner) = predicate
+        .strip_prefix("any(")
+        .and_then(|p| p.strip_suffix(')'))
+    {
+        return split_predicates(inner).any(|p| eval_cfg(p, features));
+    }
+    match predicate.strip_prefix("feature=") {
+        Some(feature) => features.iter().any(|f| *f == feature.trim_matches('"')),
+        None => false,
+    }
+}
+
+// Split a comma separated list of predicates, ignoring commas in nested parentheses
+fn split_predicates(list: &str) -> impl Iterator<Item = &str> {
+    let mut depth = 0;
+    let mut start = 0;
+    let mut parts = Vec::new();
+    for (i, c) in list.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&list[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&list[start..]);
+    parts.into_iter().filter(|p| !p.is_empty())
+}
+
+// Dependency chains (from the root package) of every crate in the target build that
+// still links std
+pub fn std_chains(metadata: &Metadata) -> Vec<Vec<String>> {
+    let Some(root) = metadata.resolve.as_ref().and_then(|r| r.root.as_deref()) else {
+        return Vec::new();
+    };
+    let mut parents = HashMap::from([(root, None)]);
+    let mut queue = VecDeque::from([root]);
+    let mut chains = Vec::new();
+    while let Some(id) = queue.pop_front() {
+        let (Some(package), Some(node)) = (metadata.package(id), metadata.node(id)) else {
+            continue;
+        };
+        if links_std(package, &node.features) {
+            let mut chain = Vec::new();
+            let mut current = Some(id);
+            while let Some(id) = current {
+                let package = metadata.package(id).unwrap();
+                chain.push(format!("{} {}", package.name, package.version));
+                current = parents[id];
+            }
+            chain.reverse();
+            chains.push(chain);
+        }
+        if package.is_proc_macro() {
+            continue;
+        }
+        for dep in node.deps.iter().filter(|d| d.is_normal()) {
+            if !parents.contains_key(dep.pkg.as_str()) {
+                parents.insert(&dep.pkg, Some(id));
+                queue.push_back(&dep.pkg);
+            }
+        }
+    }
+    chains
+}
+
+// Warn about every crate that still links std, failing with --deny-warnings
+pub fn audit_std(metadata: &Metadata, deny_warnings: bool) -> Result<()> {
+    let chains = std_chains(metadata);
+    for chain in &chains {
+        warn!(
+            "{} links std: {}",
+            chain.last().unwrap(),
+            chain.join(" -> ")
+        );
+    }
+    if deny_warnings && !chains.is_empty() {
+        anyhow::bail!("{} crates still link std", chains.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(features: &[&str]) -> Vec<String> {
+        features.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn plain_no_std() {
+        assert!(is_no_std("#![no_std]\n\npub fn f() {}\n", &[]));
+        assert!(is_no_std(
+            "//! Docs\n#![deny(missing_docs)]\n#![ no_std ]\n",
+            &[]
+        ));
+        assert!(!is_no_std("#![deny(missing_docs)]\npub fn f() {}\n", &[]));
+    }
+
+    #[test]
+    fn no_std_unless_std_feature() {
+        let source = "#![cfg_attr(not(feature = \"std\"), no_std)]\n";
+        assert!(is_no_std(source, &[]));
+        assert!(is_no_std(source, &features(&["alloc"])));
+        assert!(!is_no_std(source, &features(&["std"])));
+        // A cfg_attr applying something else is not no_std
+        assert!(!is_no_std(
+            "#![cfg_attr(not(feature = \"std\"), allow(unused))]\n",
+            &[]
+        ));
+    }
+
+    #[test]
+    fn nested_predicates() {
+        let source = "#![cfg_attr(all(not(feature = \"std\"), any(feature = \"a\", \
+                      not(test))), no_std)]\n";
+        assert!(is_no_std(source, &[]));
+        assert!(is_no_std(source, &features(&["a"])));
+        assert!(!is_no_std(source, &features(&["a", "std"])));
+
+        assert!(eval_cfg("all()", &[]));
+        assert!(!eval_cfg("any()", &[]));
+        assert!(!eval_cfg("target_os=\"none\"", &[]));
+        assert!(eval_cfg("not(target_os=\"none\")", &[]));
+        let enabled = features(&["a", "b"]);
+        assert!(eval_cfg(
+            "all(feature=\"a\",any(feature=\"c\",feature=\"b\"))",
+            &enabled
+        ));
+        assert!(!eval_cfg(
+            "all(feature=\"a\",not(any(feature=\"b\")))",
+            &enabled
+        ));
+    }
+
+    #[test]
+    fn predicates_are_split_at_the_top_level() {
+        assert_eq!(
+            split_predicates("all(a,b),not(any(c,d)),no_std").collect::<Vec<_>>(),
+            ["all(a,b)", "not(any(c,d))", "no_std"]
+        );
+        assert_eq!(split_predicates("").count(), 0);
+        assert_eq!(split_predicates("a,,b,").collect::<Vec<_>>(), ["a", "b"]);
+    }
+
+    #[test]
+    fn commented_out_attributes() {
+        assert!(!is_no_std("// #![no_std]\npub fn f() {}\n", &[]));
+        assert!(!is_no_std("//! Use `#![no_std]` in your crate\n", &[]));
+        assert!(!is_no_std(
+            "/* #![no_std] /* nested */ #![no_std] */\n",
+            &[]
+        ));
+        assert!(is_no_std("/* std by default */ #![no_std]\n", &[]));
+        // Comment markers in strings do not start comments
+        assert!(is_no_std(
+            "#![doc = \"see https://docs.rs\"]\n#![no_std]\n",
+            &[]
+        ));
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+use crate::cli::Options;
+
+// Where to save a copy of `file`: next to it with a `.bak` suffix (`.orig` is already taken
+// by cargo vendor for the original manifest), or at the same path relative to `base` in the
+// backup directory. Files outside `base` have no place in the backup directory.
+fn backup_path(file: &Path, base: &Path, opts: &Options) -> Result<PathBuf> {
+    match &opts.backup_dir {
+        Some(dir) => {
+            let relative = file.strip_prefix(base).with_context(|| {
+                format!(
+                    "cannot back up {} in {}, it is not under {}",
+                    file.display(),
+                    dir.display(),
+                    base.display()
+                )
+            })?;
+            Ok(dir.join(relative))
+        }
+        None => {
+            let mut name = file.file_name().unwrap().to_os_string();
+            name.push(".bak");
+            Ok(file.with_file_name(name))
+        }
+    }
+}
+
+// Copy a file before it gets modified, if backups were requested.
+// Existing backups are left alone so that they always hold the content before the first run.
+pub fn backup(file: &Path, base: &Path, opts: &Options) -> Result<()> {
+    if !opts.backup && opts.backup_dir.is_none() || !file.exists() {
+        return Ok(());
+    }
+    let dest = backup_path(file, base, opts)?;
+    if dest.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::copy(file, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn backup_next_to_the_file() {
+        let dir = TempDir::new();
+        let manifest = dir.write("vendor/foo/Cargo.toml", "original");
+        let opts = Options::parse(&["--backup"]);
+        backup(&manifest, dir.path(), &opts).unwrap();
+        std::fs::write(&manifest, "patched").unwrap();
+        // A later run does not overwrite the content from before the first one
+        backup(&manifest, dir.path(), &opts).unwrap();
+        assert_eq!(dir.read("vendor/foo/Cargo.toml.bak"), "original");
+    }
+
+    #[test]
+    fn backup_dir() {
+        let dir = TempDir::new();
+        let manifest = dir.write("project/vendor/foo/Cargo.toml", "original");
+        let backups = dir.path().join("backups");
+        let opts = Options::parse(&["--backup-dir", backups.to_str().unwrap()]);
+        backup(&manifest, &dir.path().join("project"), &opts).unwrap();
+        std::fs::write(&manifest, "patched").unwrap();
+        backup(&manifest, &dir.path().join("project"), &opts).unwrap();
+        assert_eq!(dir.read("backups/vendor/foo/Cargo.toml"), "original");
+        assert!(!dir
+            .path()
+            .join("project/vendor/foo/Cargo.toml.bak")
+            .exists());
+    }
+
+    #[test]
+    fn backup_dir_outside_base() {
+        let dir = TempDir::new();
+        let manifest = dir.write("elsewhere/Cargo.toml", "original");
+        let backups = dir.path().join("backups");
+        let opts = Options::parse(&["--backup-dir", backups.to_str().unwrap()]);
+        let error = backup(&manifest, &dir.path().join("project"), &opts).unwrap_err();
+        assert!(error.to_string().starts_with("cannot back up"), "{error}");
+        assert!(!backups.exists());
+    }
+
+    #[test]
+    fn no_backup_unless_requested() {
+        let dir = TempDir::new();
+        let manifest = dir.write("foo/Cargo.toml", "original");
+        backup(&manifest, dir.path(), &Options::parse(&[])).unwrap();
+        assert!(!dir.path().join("foo/Cargo.toml.bak").exists());
+        // Nor for files that do not exist (yet)
+        let missing = dir.path().join("foo/Cargo.lock");
+        backup(&missing, dir.path(), &Options::parse(&["--backup"])).unwrap();
+        assert!(!dir.path().join("foo/Cargo.lock.bak").exists());
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{bail, Result};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+use crate::cli::Color;
+
+// CARGO_HOME of every cargo invocation, from --cargo-home
+static CARGO_HOME: OnceLock<PathBuf> = OnceLock::new();
+
+// Run every cargo command with `dir` as CARGO_HOME. Cargo resolves a relative CARGO_HOME
+// against its own working directory, which is not always ours, so it is made absolute.
+pub fn set_home(dir: Option<&Path>) -> Result<()> {
+    set(&CARGO_HOME, dir)
+}
+
+// The home is set once per run, before any command is built. Setting it again to the same
+// directory is harmless, but a different one would leave the commands already run with
+// another home than the following ones, so it is an error.
+fn set(home: &OnceLock<PathBuf>, dir: Option<&Path>) -> Result<()> {
+    let Some(dir) = dir else {
+        return Ok(());
+    };
+    let dir = std::path::absolute(dir)?;
+    let current = home.get_or_init(|| dir.clone());
+    if *current != dir {
+        bail!(
+            "CARGO_HOME is already {}, cannot change it to {}",
+            current.display(),
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+// CARGO_HOME of the cargo commands: --cargo-home, or else the one cargo itself would use
+pub fn home() -> Result<PathBuf> {
+    if let Some(home) = CARGO_HOME.get() {
+        return Ok(home.clone());
+    }
+    if let Some(home) = std::env::var_os("CARGO_HOME") {
+        return Ok(std::path::absolute(home)?);
+    }
+    match std::env::var_os("HOME") {
+        Some(home) => Ok(PathBuf::from(home).join(".cargo")),
+        None => bail!("cannot find CARGO_HOME: neither it nor HOME is set"),
+    }
+}
+
+// A cargo invocation whose output we capture and possibly show in our own messages,
+// so it must not contain color codes
+pub fn captured(subcommand: &str) -> Command {
+    cargo(subcommand, Color::Never)
+}
+
+// A cargo invocation writing straight to the terminal
+pub fn inherited(subcommand: &str, color: Color) -> Command {
+    cargo(subcommand, color)
+}
+
+fn cargo(subcommand: &str, color: Color) -> Command {
+    command(subcommand, color, CARGO_HOME.get())
+}
+
+fn command(subcommand: &str, color: Color, home: Option<&PathBuf>) -> Command {
+    let mut cmd = Command::new("cargo");
+    cmd.arg(subcommand).args(["--color", color.as_str()]);
+    if let Some(home) = home {
+        cmd.env("CARGO_HOME", home);
+    }
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captured_output_is_never_colored() {
+        let cmd = captured("add");
+        assert_eq!(cmd.get_program(), "cargo");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            ["add", "--color", "never"]
+        );
+    }
+
+    #[test]
+    fn inherited_output_follows_color() {
+        for color in [Color::Auto, Color::Always, Color::Never] {
+            let cmd = inherited("vendor", color);
+            assert_eq!(
+                cmd.get_args().collect::<Vec<_>>(),
+                ["vendor", "--color", color.as_str()]
+            );
+        }
+    }
+
+    #[test]
+    fn cargo_home_is_passed_to_cargo() {
+        let cmd = command("vendor", Color::Never, Some(&PathBuf::from("/cache")));
+        assert_eq!(
+            cmd.get_envs().collect::<Vec<_>>(),
+            [("CARGO_HOME".as_ref(), Some("/cache".as_ref()))]
+        );
+        // Without --cargo-home, cargo inherits ours
+        let cmd = command("vendor", Color::Never, None);
+        assert_eq!(cmd.get_envs().count(), 0);
+    }
+
+    #[test]
+    fn cargo_home_is_set_once() {
+        let home = OnceLock::new();
+        set(&home, None).unwrap();
+        assert!(home.get().is_none());
+        set(&home, Some(Path::new("cache"))).unwrap();
+        let absolute = std::env::current_dir().unwrap().join("cache");
+        assert_eq!(home.get(), Some(&absolute));
+        set(&home, Some(&absolute)).unwrap();
+        set(&home, None).unwrap();
+        assert!(set(&home, Some(Path::new("/other"))).is_err());
+        assert_eq!(home.get(), Some(&absolute));
+    }
+}
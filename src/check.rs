@@ -0,0 +1,103 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::{
+    cargo,
+    cli::{Format, Options},
+};
+
+// Source replacement config printed by cargo vendor, with the vendor directory made absolute
+// so that it can be handed to cargo from an arbitrary location
+fn vendor_config(vendor_config: &str, dir: &Path) -> Result<PathBuf> {
+    let mut config: toml::Table = vendor_config.parse()?;
+    if let Some(sources) = config.get_mut("source").and_then(|s| s.as_table_mut()) {
+        for (_, source) in sources.iter_mut() {
+            if let Some(directory) = source.get_mut("directory") {
+                let absolute = dir.join(directory.as_str().unwrap_or_default());
+                *directory = absolute.to_string_lossy().into_owned().into();
+            }
+        }
+    }
+    let path = std::env::temp_dir().join(format!("atomic-patch-{}.toml", std::process::id()));
+    std::fs::write(&path, toml::to_string(&config)?)?;
+    Ok(path)
+}
+
+// The toolchain may be picked with --check-env
+fn is_nightly(opts: &Options) -> Result<bool> {
+    let output = cargo::captured("--version")
+        .envs(opts.check_env.iter().map(|(key, value)| (key, value)))
+        .output()?;
+    let version = String::from_utf8_lossy(&output.stdout);
+    Ok(version.contains("-nightly") || version.contains("-dev"))
+}
+
+// Build the patched project with `cargo check` against the patched vendored sources.
+// With --minimal-versions the lockfile is first regenerated with the oldest versions allowed
+// by the requirements (this needs a nightly cargo) to catch requirements that are too loose,
+// and restored afterwards. Resolving old versions needs the registry, so in that case the
+// build does not use the vendored sources.
+// --rustflags only applies to the build itself, --check-env to all the cargo commands of
+// this step.
+pub fn check(manifest_path: &Path, vendor_stdout: &str, opts: &Options) -> Result<()> {
+    let dir = manifest_path.parent().unwrap();
+    let lockfile = dir.join("Cargo.lock");
+    let minimal_versions = opts.minimal_versions && is_nightly(opts)?;
+    if opts.minimal_versions && !minimal_versions {
+        warn!("--minimal-versions needs a nightly toolchain, checking with the current lockfile");
+    }
+
+    let mut cmd = cargo::inherited("check", opts.color);
+    cmd.arg("--manifest-path")
+        .arg(manifest_path)
+        .args(opts.features.to_args());
+    if let Some(target) = &opts.target {
+        cmd.args(["--target", target]);
+    }
+    if opts.format != Format::Human {
+        cmd.stdout(std::io::stderr());
+    }
+    if let Some(rustflags) = &opts.rustflags {
+        cmd.env("RUSTFLAGS", rustflags);
+    }
+    cmd.envs(opts.check_env.iter().map(|(key, value)| (key, value)));
+
+    let config = if minimal_versions {
+        None
+    } else {
+        Some(vendor_config(vendor_stdout, dir)?)
+    };
+    if let Some(config) = &config {
+        cmd.arg("--config").arg(config);
+    }
+
+    let original_lockfile = std::fs::read(&lockfile).ok();
+    let result = (|| {
+        if minimal_versions {
+            info!("Resolving minimal versions");
+            let status = cargo::inherited("update", opts.color)
+                .envs(opts.check_env.iter().map(|(key, value)| (key, value)))
+                .args(["-Z", "minimal-versions"])
+                .arg("--manifest-path")
+                .arg(manifest_path)
+                .status()?;
+            if !status.success() {
+                anyhow::bail!("cargo update -Z minimal-versions failed");
+            }
+        }
+        info!("Checking the patched project");
+        if !cmd.status()?.success() {
+            anyhow::bail!("cargo check failed on the patched project");
+        }
+        Ok(())
+    })();
+
+    if let Some(original) = original_lockfile {
+        std::fs::write(&lockfile, original)?;
+    }
+    if let Some(config) = config {
+        let _ = std::fs::remove_file(config);
+    }
+    result
+}
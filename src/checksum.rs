@@ -0,0 +1,223 @@
+use anyhow::{Context, Result};
+use serde_json::value::RawValue;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+/// What to do with the checksums of a patched crate
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum ChecksumMode {
+    /// Drop all the checksums of patched crates
+    Clear,
+    /// Only drop the checksums of the files modified by the patch
+    Modified,
+}
+
+// Top level of a checksum file, `{"files": {...}, "package": "..."}`. Values borrow the raw
+// JSON they were read from, so that only the `files` map is ever parsed, and only when
+// entries need to be removed.
+type Checksums<'a> = BTreeMap<String, &'a RawValue>;
+
+fn read(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))
+}
+
+fn parse<'a>(contents: &'a str, path: &Path) -> Result<Checksums<'a>> {
+    serde_json::from_str(contents).with_context(|| format!("invalid {}", path.display()))
+}
+
+// Checksums of the individual files, keyed by path
+fn files<'a>(checksums: &Checksums<'a>, path: &Path) -> Result<Checksums<'a>> {
+    match checksums.get("files") {
+        Some(files) => serde_json::from_str(files.get())
+            .with_context(|| format!("invalid `files` in {}", path.display())),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// Cargo saves a checksum for each file in the vendor directory (in `.cargo-checksum.json`,
+/// unless the tree was produced by some other tool).
+/// Removing such file will cause cargo to ignore it and it's more convenient than recomputing it.
+/// There is a single checksum file at the root of each vendored crate, shared by all the
+/// manifests it contains.
+/// With `ChecksumMode::Modified` only the entries of the files we touched are removed, so that
+/// cargo keeps verifying the rest of the sources.
+pub fn remove_cargo_toml_checksum(
+    crate_root: &Path,
+    checksum_file: &str,
+    mode: ChecksumMode,
+    modified: &[PathBuf],
+) -> Result<()> {
+    let metadata_path = crate_root.join(checksum_file);
+    // Sources that were not vendored by cargo have nothing to invalidate
+    if !metadata_path.exists() {
+        return Ok(());
+    }
+    let contents = read(&metadata_path)?;
+    let mut checksums = parse(&contents, &metadata_path)?;
+    let files = match mode {
+        ChecksumMode::Clear => BTreeMap::new(),
+        ChecksumMode::Modified => {
+            let mut files = files(&checksums, &metadata_path)?;
+            for path in modified {
+                files.remove(&checksum_key(path));
+            }
+            files
+        }
+    };
+    let files = serde_json::value::to_raw_value(&files)?;
+    checksums.insert("files".into(), &files);
+    std::fs::write(&metadata_path, serde_json::to_vec(&checksums)?)
+        .with_context(|| format!("cannot write {}", metadata_path.display()))
+}
+
+/// Whether the checksum file of a crate is in the state `remove_cargo_toml_checksum` leaves it in
+pub fn is_cleared(
+    crate_root: &Path,
+    checksum_file: &str,
+    mode: ChecksumMode,
+    modified: &[PathBuf],
+) -> Result<bool> {
+    let metadata_path = crate_root.join(checksum_file);
+    if !metadata_path.exists() {
+        return Ok(true);
+    }
+    let contents = read(&metadata_path)?;
+    let files = files(&parse(&contents, &metadata_path)?, &metadata_path)?;
+    Ok(match mode {
+        ChecksumMode::Clear => files.is_empty(),
+        ChecksumMode::Modified => !modified
+            .iter()
+            .any(|path| files.contains_key(&checksum_key(path))),
+    })
+}
+
+// Cargo always uses forward slashes in the checksum file
+fn checksum_key(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const CHECKSUMS: &str = r#"{"files":{"Cargo.lock":"01","Cargo.toml":"02","build.rs":"03","src/lib.rs":"04"},"package":"05"}"#;
+
+    fn modified() -> Vec<PathBuf> {
+        vec!["Cargo.toml".into(), "Cargo.lock".into()]
+    }
+
+    #[test]
+    fn modified_mode_keeps_the_other_entries() {
+        let dir = TempDir::new();
+        dir.write(".cargo-checksum.json", CHECKSUMS);
+        let mode = ChecksumMode::Modified;
+        assert!(!is_cleared(dir.path(), ".cargo-checksum.json", mode, &modified()).unwrap());
+        remove_cargo_toml_checksum(dir.path(), ".cargo-checksum.json", mode, &modified()).unwrap();
+        assert_eq!(
+            dir.read(".cargo-checksum.json"),
+            r#"{"files":{"build.rs":"03","src/lib.rs":"04"},"package":"05"}"#
+        );
+        assert!(is_cleared(dir.path(), ".cargo-checksum.json", mode, &modified()).unwrap());
+    }
+
+    #[test]
+    fn clear_mode_drops_every_entry() {
+        let dir = TempDir::new();
+        dir.write(".cargo-checksum.json", CHECKSUMS);
+        let mode = ChecksumMode::Clear;
+        remove_cargo_toml_checksum(dir.path(), ".cargo-checksum.json", mode, &modified()).unwrap();
+        assert_eq!(
+            dir.read(".cargo-checksum.json"),
+            r#"{"files":{},"package":"05"}"#
+        );
+        assert!(is_cleared(dir.path(), ".cargo-checksum.json", mode, &modified()).unwrap());
+    }
+
+    #[test]
+    fn nested_paths_use_forward_slashes() {
+        let dir = TempDir::new();
+        dir.write(
+            ".cargo-checksum.json",
+            r#"{"files":{"sub/Cargo.toml":"01","src/lib.rs":"02"},"package":"03"}"#,
+        );
+        let modified = [Path::new("sub").join("Cargo.toml")];
+        remove_cargo_toml_checksum(
+            dir.path(),
+            ".cargo-checksum.json",
+            ChecksumMode::Modified,
+            &modified,
+        )
+        .unwrap();
+        assert_eq!(
+            dir.read(".cargo-checksum.json"),
+            r#"{"files":{"src/lib.rs":"02"},"package":"03"}"#
+        );
+    }
+
+    #[test]
+    fn missing_checksum_file() {
+        let dir = TempDir::new();
+        let mode = ChecksumMode::Modified;
+        remove_cargo_toml_checksum(dir.path(), ".cargo-checksum.json", mode, &modified()).unwrap();
+        assert!(!dir.path().join(".cargo-checksum.json").exists());
+        assert!(is_cleared(dir.path(), ".cargo-checksum.json", mode, &modified()).unwrap());
+    }
+
+    #[test]
+    fn custom_checksum_file() {
+        let dir = TempDir::new();
+        dir.write("checksums.json", CHECKSUMS);
+        dir.write(".cargo-checksum.json", CHECKSUMS);
+        let mode = ChecksumMode::Modified;
+        assert!(!is_cleared(dir.path(), "checksums.json", mode, &modified()).unwrap());
+        remove_cargo_toml_checksum(dir.path(), "checksums.json", mode, &modified()).unwrap();
+        assert_eq!(
+            dir.read("checksums.json"),
+            r#"{"files":{"build.rs":"03","src/lib.rs":"04"},"package":"05"}"#
+        );
+        assert!(is_cleared(dir.path(), "checksums.json", mode, &modified()).unwrap());
+        // The default name is not a fallback
+        assert_eq!(dir.read(".cargo-checksum.json"), CHECKSUMS);
+    }
+
+    #[test]
+    fn large_checksum_file() {
+        let dir = TempDir::new();
+        let files = (0..20_000)
+            .map(|i| (format!("src/file-{i}.rs"), format!("{i:064x}")))
+            .chain([("Cargo.toml".into(), "00".into())])
+            .collect::<BTreeMap<_, _>>();
+        let checksums = serde_json::json!({ "files": files, "package": "01" });
+        dir.write(".cargo-checksum.json", &checksums.to_string());
+        let mode = ChecksumMode::Modified;
+        remove_cargo_toml_checksum(dir.path(), ".cargo-checksum.json", mode, &modified()).unwrap();
+
+        let checksums: serde_json::Value =
+            serde_json::from_str(&dir.read(".cargo-checksum.json")).unwrap();
+        let left = checksums["files"].as_object().unwrap();
+        assert_eq!(left.len(), 20_000);
+        assert!(!left.contains_key("Cargo.toml"));
+        assert_eq!(left["src/file-19999.rs"], format!("{:064x}", 19_999));
+        assert_eq!(checksums["package"], "01");
+    }
+
+    #[test]
+    fn truncated_checksum_file() {
+        let dir = TempDir::new();
+        let truncated = &CHECKSUMS[..CHECKSUMS.len() / 2];
+        dir.write(".cargo-checksum.json", truncated);
+        for mode in [ChecksumMode::Clear, ChecksumMode::Modified] {
+            let removed =
+                remove_cargo_toml_checksum(dir.path(), ".cargo-checksum.json", mode, &modified());
+            assert!(removed.is_err());
+            assert!(is_cleared(dir.path(), ".cargo-checksum.json", mode, &modified()).is_err());
+        }
+        assert_eq!(dir.read(".cargo-checksum.json"), truncated);
+    }
+}
@@ -0,0 +1,431 @@
+use cargo_atomic_patch::checksum::ChecksumMode;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+// Cargo invokes subcommands as `cargo-atomic-patch atomic-patch [ARGS]`
+#[derive(Parser)]
+#[command(name = "cargo", bin_name = "cargo")]
+pub enum Cargo {
+    AtomicPatch(Cli),
+}
+
+#[derive(Args)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub opts: Options,
+}
+
+// Options shared by a plain run and the subcommands doing (part of) the same work
+// Some can also be set in the config file or the environment, see src/config.rs
+#[derive(Args)]
+pub struct Options {
+    #[command(flatten)]
+    pub features: FeatureArgs,
+    /// Read settings from FILE (defaults to atomic-patch.toml in the current directory, if any)
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Version requirement of atomic-core (latest by default)
+    #[arg(long, value_name = "REQ")]
+    pub atomic_core_version: Option<String>,
+    /// Version requirement of atomic-core in the dependencies, if it should differ from the
+    /// one of the project
+    #[arg(long, value_name = "REQ")]
+    pub nested_atomic_core_version: Option<String>,
+    /// Require the exact version of atomic-core the project resolved to in the dependencies,
+    /// so that they all use the vendored copy
+    #[arg(long, conflicts_with_all = ["nested_atomic_core_version", "in_place"])]
+    pub pin_nested: bool,
+    /// Run every cargo command (vendoring, adding atomic-core, --check...) with DIR as
+    /// CARGO_HOME. Without network access, point it at a CARGO_HOME whose registry cache
+    /// already holds the dependencies and atomic-core (e.g. from a run with the same
+    /// --cargo-home while online) and set `net.offline = true` in its config.toml.
+    /// --deny-yanked still needs the network.
+    #[arg(long, value_name = "DIR")]
+    pub cargo_home: Option<PathBuf>,
+    /// Take atomic-core from a local checkout in DIR instead of crates.io
+    #[arg(long, value_name = "DIR")]
+    pub atomic_core_path: Option<PathBuf>,
+    /// Target triple of the build: dependencies only used on other platforms are left alone
+    #[arg(long, value_name = "TRIPLE")]
+    pub target: Option<String>,
+    /// After patching, list the crates that still link std in the target build and the
+    /// dependency chain pulling them in
+    #[arg(long, conflicts_with = "in_place")]
+    pub audit_std: bool,
+    /// After patching, build the project with `cargo check` against the patched sources
+    #[arg(long, conflicts_with = "in_place")]
+    pub check: bool,
+    /// Resolve the oldest allowed dependency versions for the --check build (needs nightly)
+    #[arg(long, requires = "check")]
+    pub minimal_versions: bool,
+    /// RUSTFLAGS of the --check build, e.g. to point it at a cross sysroot with `--sysroot`
+    #[arg(
+        long,
+        value_name = "FLAGS",
+        requires = "check",
+        allow_hyphen_values = true
+    )]
+    pub rustflags: Option<String>,
+    /// Set an environment variable for the --check build, such as RUSTUP_TOOLCHAIN to build
+    /// with a cross toolchain (can be repeated). Only the --check step honors this and
+    /// --rustflags: the other cargo commands do not compile anything.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env, requires = "check")]
+    pub check_env: Vec<(String, String)>,
+    /// Features to enable on atomic-core, for the crates without their own in the config file
+    /// [default: critical-section]
+    #[arg(long, value_name = "FEATURES", value_delimiter = ',')]
+    pub atomic_core_features: Option<Vec<String>>,
+    /// Disable the default features of atomic-core, so that only the ones of
+    /// --atomic-core-features are enabled. Recommended: together with the default features
+    /// this adds `default-features = false, features = ["critical-section"]`, which does not
+    /// rely on anything but a critical section implementation.
+    #[arg(long)]
+    pub no_default_atomic_core_features: bool,
+    /// If cargo add does not recognize some features when patching a crate, drop them with a
+    /// warning and try again instead of failing
+    #[arg(long)]
+    pub lenient_features: bool,
+    /// Also add the replacement to the dev-dependencies of crates with no_std integration
+    /// tests. Whether cargo then builds these tests against it depends on the test harness
+    /// and the target, so they may still need manual attention.
+    #[arg(long)]
+    pub replace_core_in_tests: bool,
+    /// Sort the dependencies of every patched manifest by name, so that patching again after
+    /// re-vendoring gives the same manifests. Without it, cargo add only keeps the order of
+    /// tables that are already sorted and adds core at the end of the others.
+    #[arg(long)]
+    pub sort_deps: bool,
+    /// Fail if atomic-core resolves to an older version
+    #[arg(long, value_name = "VERSION")]
+    pub min_atomic_core_version: Option<semver::Version>,
+    /// Fail if atomic-core resolves to a yanked version (as recorded in the registry index
+    /// cargo keeps in CARGO_HOME)
+    #[arg(long)]
+    pub deny_yanked: bool,
+    /// Only check that the project and its vendor directory (or the --in-place one) are
+    /// consistently patched, failing with the list of crates that are not otherwise
+    #[arg(long, conflicts_with = "dry_run")]
+    pub check_only: bool,
+    /// Print what would be patched and skipped, without modifying anything
+    #[arg(long, conflicts_with = "in_place")]
+    pub dry_run: bool,
+    /// Treat warnings as errors
+    #[arg(long)]
+    pub deny_warnings: bool,
+    /// Only patch CRATE and the dependencies reachable from it (can be repeated).
+    /// Mind that --exclude takes precedence: excluded crates are not patched even if they are
+    /// part of the subtree, but their own dependencies still are.
+    #[arg(long, value_name = "CRATE", conflicts_with = "in_place")]
+    pub only: Vec<String>,
+    /// Only patch the no_std crates built with one of the --atomic-features, which are
+    /// usually the ones that need atomics for the build at hand. Crates using atomics
+    /// unconditionally are missed.
+    #[arg(long, conflicts_with = "in_place")]
+    pub atomic_feature_gated: bool,
+    /// Features taken to gate the use of atomics for --atomic-feature-gated
+    #[arg(
+        long,
+        value_name = "FEATURES",
+        value_delimiter = ',',
+        default_value = "atomic,atomics,portable-atomic,critical-section"
+    )]
+    pub atomic_features: Vec<String>,
+    /// Never patch CRATE, like the replacement and its dependencies (can be repeated).
+    /// Case and the difference between `-` and `_` do not matter.
+    #[arg(long, value_name = "CRATE")]
+    pub no_patch: Vec<String>,
+    /// Do not patch CRATE (can be repeated)
+    #[arg(long, value_name = "CRATE")]
+    pub exclude: Vec<String>,
+    /// Do not patch crates declaring a native library with `links`
+    /// (they are listed in the report either way)
+    #[arg(long)]
+    pub skip_links: bool,
+    /// Point the path dependencies of vendored crates that do not resolve (e.g. between
+    /// members of a vendored workspace) at the vendored crate of the same name, if there is
+    /// exactly one matching the version requirement. Without it they are only reported.
+    #[arg(long)]
+    pub rewrite_path_deps: bool,
+    /// Honor the source replacements already configured for the project when vendoring.
+    /// By default cargo ignores them and vendors pristine sources from the original registries,
+    /// which is what a first run needs; this is for re-vendoring from an existing setup.
+    #[arg(long, conflicts_with = "in_place")]
+    pub respect_source_config: bool,
+    /// Only patch the vendored crates with files changed (or added) since the git REF, e.g. to
+    /// re-patch a committed vendor directory after re-vendoring a few dependencies
+    #[arg(long, value_name = "REF")]
+    pub since: Option<String>,
+    /// Add a [workspace] stub to every patched crate. By default it is only added when the
+    /// crate would otherwise be considered part of an enclosing workspace.
+    #[arg(long)]
+    pub always_workspace_stub: bool,
+    /// Resolver set in the workspace stub added to vendored crates
+    #[arg(long, value_name = "VERSION", default_value = "2")]
+    pub workspace_resolver: String,
+    /// How deep to look for manifests in the vendor directory
+    /// (the default only finds the top level manifest of each vendored crate)
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    pub max_depth: usize,
+    /// Refuse to patch more than N vendored crates, in case the tool was pointed at the
+    /// wrong directory
+    #[arg(long, value_name = "N")]
+    pub max_crates: Option<usize>,
+    /// Patch the crates even if there are more than --max-crates
+    #[arg(long, requires = "max_crates")]
+    pub force: bool,
+    /// Save a copy of every manifest and checksum file before modifying it, as `<FILE>.bak`.
+    /// Files that already have a backup are not backed up again.
+    #[arg(long)]
+    pub backup: bool,
+    /// Save backups in DIR instead, at the same path relative to the project (implies --backup)
+    #[arg(long, value_name = "DIR")]
+    pub backup_dir: Option<PathBuf>,
+    /// Write the name and version of every patched crate, together with the atomic-core
+    /// spec applied, to FILE (TOML if it ends in .toml, JSON otherwise)
+    #[arg(long, value_name = "FILE")]
+    pub emit_manifest: Option<PathBuf>,
+    /// Fail if the hash of the patched tree, printed at the end of the run, is not HASH.
+    /// It covers the replacement spec and the patched manifests, not the rest of the sources.
+    #[arg(long, value_name = "HASH")]
+    pub expect_hash: Option<String>,
+    /// Log why each crate was patched or not
+    #[arg(long)]
+    pub explain: bool,
+    /// Log more details, such as the full error of every crate that failed
+    /// (RUST_LOG takes precedence)
+    #[arg(short, long)]
+    pub verbose: bool,
+    /// How to report the outcome of the run on stdout
+    #[arg(long, value_enum, default_value_t = Format::Human)]
+    pub format: Format,
+    /// Coloring of our own output and of cargo commands writing to the terminal.
+    /// Output of cargo commands shown in error messages is never colored.
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = Color::Auto)]
+    pub color: Color,
+    /// Number of crates to patch in parallel (defaults to the number of CPUs).
+    /// `--jobs 1` patches one crate at a time, in path order.
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<usize>,
+    /// Do everything on the current thread, without starting a thread pool. This is also
+    /// what happens when worker threads cannot be spawned.
+    #[arg(long, conflicts_with = "jobs")]
+    pub no_parallel: bool,
+    /// After patching, remove the directories not needed to build from every vendored crate,
+    /// along with the targets the manifests declare in them
+    #[arg(long)]
+    pub prune: bool,
+    /// Top level directories removed by --prune. Mind that docs are sometimes included in the
+    /// sources (e.g. with `include_str!`), which is why they are not pruned by default.
+    #[arg(
+        long,
+        value_name = "DIRS",
+        value_delimiter = ',',
+        default_value = "tests,examples,benches"
+    )]
+    pub prune_dirs: Vec<String>,
+    /// What to do with the checksums of vendored crates
+    #[arg(long, value_enum, default_value_t = ChecksumMode::Clear)]
+    pub checksum_mode: ChecksumMode,
+    /// Name of the checksum file at the root of each vendored crate
+    #[arg(long, value_name = "NAME", default_value = ".cargo-checksum.json")]
+    pub checksum_file: String,
+    /// After patching, read the checksum files back and fail if any still checks a modified
+    /// file, instead of finding out when cargo rejects the build
+    #[arg(long)]
+    pub verify_checksums: bool,
+    /// Skip vendoring and patch the crate sources already laid out in DIR,
+    /// one crate per subdirectory. The project manifest is left untouched.
+    #[arg(long, value_name = "DIR")]
+    pub in_place: Option<PathBuf>,
+    /// Copy the vendored crates to DIR and patch the copies, leaving the originals untouched.
+    /// DIR must be empty or not exist yet. The project manifest and the crates it patches
+    /// with a path are still patched where they are.
+    #[arg(long, value_name = "DIR", conflicts_with = "check")]
+    pub out_dir: Option<PathBuf>,
+    /// Record the progress of the run in .atomic-patch-progress next to the vendor directory
+    /// and, if a run with the same options was interrupted, pick up where it stopped without
+    /// vendoring again. The file is removed once every crate is patched.
+    #[arg(long, conflicts_with = "out_dir")]
+    pub resume: bool,
+    /// Patch exactly the manifest at PATH (which must be a Cargo.toml) instead of vendoring
+    /// and looking for crates, and clear the checksums of the crate it belongs to. Can be
+    /// repeated. The project manifest is left untouched.
+    #[arg(
+        long = "manifest",
+        value_name = "PATH",
+        conflicts_with_all = ["in_place", "out_dir", "resume", "since", "check", "audit_std", "dry_run", "prune"]
+    )]
+    pub manifests: Vec<PathBuf>,
+    /// Record a chrome trace of the run into FILE
+    #[cfg(feature = "chrome")]
+    #[arg(long, value_name = "FILE")]
+    pub trace_chrome: Option<PathBuf>,
+}
+
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.into(), value.into()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{s}`"))
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Work out what a run would do, without modifying anything, and save it as a plan
+    /// (see src/plan.rs for its format)
+    Plan {
+        /// Where to write the plan (stdout by default)
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        opts: Options,
+    },
+    /// Execute exactly the actions of a plan created with `plan`
+    Apply {
+        plan: PathBuf,
+        #[command(flatten)]
+        opts: Options,
+    },
+    /// Print the dependency tree of the project, with what patching would do to each crate and
+    /// whether it links std, without modifying anything
+    Tree {
+        #[command(flatten)]
+        opts: Options,
+    },
+    /// Same as --check-only
+    Verify {
+        #[command(flatten)]
+        opts: Options,
+    },
+    /// Print a completion script for the cargo-atomic-patch binary to stdout. It completes
+    /// `cargo-atomic-patch atomic-patch [ARGS]` and leaves the completion of cargo alone.
+    #[command(hide = true)]
+    Completions { shell: clap_complete::Shell },
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    /// A summary in the log output
+    Human,
+    /// A JSON report once the run is over
+    Json,
+    /// One JSON object per line for each event, as it happens (schema in src/events.rs)
+    Jsonl,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Color::Auto => "auto",
+            Color::Always => "always",
+            Color::Never => "never",
+        }
+    }
+}
+
+/// Feature selection of the project being patched
+#[derive(Args, Clone, Default)]
+pub struct FeatureArgs {
+    /// Space or comma separated list of features to activate
+    #[arg(short = 'F', long, value_delimiter = ',')]
+    pub features: Vec<String>,
+    /// Activate all available features
+    #[arg(long)]
+    pub all_features: bool,
+    /// Do not activate the `default` feature
+    #[arg(long)]
+    pub no_default_features: bool,
+}
+
+impl FeatureArgs {
+    pub fn is_default(&self) -> bool {
+        self.features.is_empty() && !self.all_features && !self.no_default_features
+    }
+
+    // Arguments to forward to cargo commands resolving the dependency graph
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if !self.features.is_empty() {
+            args.push("--features".into());
+            args.push(self.features.join(","));
+        }
+        if self.all_features {
+            args.push("--all-features".into());
+        }
+        if self.no_default_features {
+            args.push("--no-default-features".into());
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+impl Options {
+    // The options of `cargo atomic-patch ARGS`
+    pub fn parse(args: &[&str]) -> Self {
+        let Cargo::AtomicPatch(cli) =
+            Cargo::parse_from(["cargo", "atomic-patch"].iter().chain(args));
+        cli.opts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_features() {
+        let features = FeatureArgs::default();
+        assert!(features.is_default());
+        assert!(features.to_args().is_empty());
+    }
+
+    #[test]
+    fn feature_args() {
+        let features = FeatureArgs {
+            features: vec!["std".into(), "serde".into()],
+            all_features: true,
+            no_default_features: true,
+        };
+        assert!(!features.is_default());
+        assert_eq!(
+            features.to_args(),
+            [
+                "--features",
+                "std,serde",
+                "--all-features",
+                "--no-default-features"
+            ]
+        );
+    }
+
+    #[test]
+    fn feature_args_from_the_command_line() {
+        let Cargo::AtomicPatch(cli) = Cargo::parse_from([
+            "cargo",
+            "atomic-patch",
+            "-F",
+            "a,b",
+            "--features",
+            "c",
+            "--no-default-features",
+        ]);
+        let features = cli.opts.features;
+        assert!(!features.is_default());
+        assert_eq!(
+            features.to_args(),
+            ["--features", "a,b,c", "--no-default-features"]
+        );
+        let Cargo::AtomicPatch(cli) = Cargo::parse_from(["cargo", "atomic-patch"]);
+        assert!(cli.opts.features.is_default());
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::Path};
+
+use crate::cli::Options;
+
+// Looked up in the current directory when --config is not given
+const DEFAULT_PATH: &str = "atomic-patch.toml";
+
+// Settings read from the config file:
+//
+// # Defaults for --atomic-core-version, --atomic-core-features and --no-patch
+// atomic_core_version = "0.0.1"
+// atomic_core_features = ["critical-section"]
+// no_patch = ["my-atomics"]
+//
+// # Features of atomic-core for specific crates, instead of --atomic-core-features
+// [crate_features]
+// heapless = ["critical-section"]
+// spin = []
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    atomic_core_version: Option<String>,
+    atomic_core_features: Option<Vec<String>>,
+    no_patch: Option<Vec<String>>,
+    #[serde(default)]
+    pub crate_features: BTreeMap<String, Vec<String>>,
+}
+
+// Comma separated list in an environment variable
+fn env_list(key: &str) -> Option<Vec<String>> {
+    let value = std::env::var(key).ok()?;
+    Some(
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(String::from)
+            .collect(),
+    )
+}
+
+impl Config {
+    // Fill in the options not given on the command line. The config file takes precedence
+    // over the environment variables, which are the lowest priority source:
+    // ATOMIC_PATCH_VERSION, ATOMIC_PATCH_FEATURES and ATOMIC_PATCH_NO_PATCH
+    // (the last two comma separated)
+    pub fn resolve(&self, opts: &mut Options) {
+        opts.atomic_core_version = opts
+            .atomic_core_version
+            .take()
+            .or_else(|| self.atomic_core_version.clone())
+            .or_else(|| std::env::var("ATOMIC_PATCH_VERSION").ok());
+        opts.atomic_core_features = opts
+            .atomic_core_features
+            .take()
+            .or_else(|| self.atomic_core_features.clone())
+            .or_else(|| env_list("ATOMIC_PATCH_FEATURES"))
+            .or_else(|| Some(vec!["critical-section".into()]));
+        if opts.no_patch.is_empty() {
+            opts.no_patch = self
+                .no_patch
+                .clone()
+                .or_else(|| env_list("ATOMIC_PATCH_NO_PATCH"))
+                .unwrap_or_default();
+        }
+    }
+
+    pub fn load(opts: &Options) -> Result<Self> {
+        let path = match &opts.config {
+            Some(path) => path.as_path(),
+            None if Path::new(DEFAULT_PATH).is_file() => Path::new(DEFAULT_PATH),
+            None => return Ok(Config::default()),
+        };
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("invalid config {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+atomic_core_version = "0.2"
+atomic_core_features = ["c"]
+no_patch = ["y"]
+
+[crate_features]
+spin = []
+"#;
+
+    fn resolved(config: &Config, args: &[&str]) -> Options {
+        let mut opts = Options::parse(args);
+        config.resolve(&mut opts);
+        opts
+    }
+
+    // A single test, as the environment is shared by every test of the process
+    #[test]
+    fn precedence() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        assert_eq!(config.crate_features["spin"], Vec::<String>::new());
+        let cli = [
+            "--atomic-core-version",
+            "0.3",
+            "--atomic-core-features",
+            "d",
+            "--no-patch",
+            "z",
+        ];
+
+        for key in [
+            "ATOMIC_PATCH_VERSION",
+            "ATOMIC_PATCH_FEATURES",
+            "ATOMIC_PATCH_NO_PATCH",
+        ] {
+            std::env::remove_var(key);
+        }
+        let opts = resolved(&Config::default(), &[]);
+        assert_eq!(opts.atomic_core_version, None);
+        assert_eq!(
+            opts.atomic_core_features,
+            Some(vec!["critical-section".into()])
+        );
+        assert!(opts.no_patch.is_empty());
+
+        std::env::set_var("ATOMIC_PATCH_VERSION", "0.1");
+        std::env::set_var("ATOMIC_PATCH_FEATURES", "a, b,");
+        std::env::set_var("ATOMIC_PATCH_NO_PATCH", "x");
+        let opts = resolved(&Config::default(), &[]);
+        assert_eq!(opts.atomic_core_version.as_deref(), Some("0.1"));
+        assert_eq!(
+            opts.atomic_core_features,
+            Some(vec!["a".into(), "b".into()])
+        );
+        assert_eq!(opts.no_patch, ["x"]);
+
+        let opts = resolved(&config, &[]);
+        assert_eq!(opts.atomic_core_version.as_deref(), Some("0.2"));
+        assert_eq!(opts.atomic_core_features, Some(vec!["c".into()]));
+        assert_eq!(opts.no_patch, ["y"]);
+
+        let opts = resolved(&config, &cli);
+        assert_eq!(opts.atomic_core_version.as_deref(), Some("0.3"));
+        assert_eq!(opts.atomic_core_features, Some(vec!["d".into()]));
+        assert_eq!(opts.no_patch, ["z"]);
+
+        // An empty list given in the environment is not replaced by the default features
+        std::env::set_var("ATOMIC_PATCH_FEATURES", "");
+        let opts = resolved(&Config::default(), &[]);
+        assert_eq!(opts.atomic_core_features, Some(vec![]));
+
+        for key in [
+            "ATOMIC_PATCH_VERSION",
+            "ATOMIC_PATCH_FEATURES",
+            "ATOMIC_PATCH_NO_PATCH",
+        ] {
+            std::env::remove_var(key);
+        }
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert!(toml::from_str::<Config>("atomic_core_verison = \"0.2\"").is_err());
+    }
+}
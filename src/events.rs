@@ -0,0 +1,97 @@
+use serde::Serialize;
+use std::{
+    io::Write,
+    path::PathBuf,
+    sync::mpsc::{self, Sender},
+    thread::JoinHandle,
+};
+
+use cargo_atomic_patch::skip::SkipReason;
+
+// Progress events printed to stdout with `--format jsonl`, one JSON object per line.
+// Every object has an "event" field naming its kind, the other fields depend on it:
+//
+// {"event":"crate-started","crate":"<dir>","manifest":"<path>"}
+// {"event":"crate-patched","crate":"<dir>","manifest":"<path>"}
+// {"event":"crate-skipped","crate":"<dir>","manifest":"<path>","reason":"<reason>"}
+// {"event":"crate-failed","crate":"<dir>","manifest":"<path>","error":"<message>"}
+// {"event":"done","patched":<n>,"skipped":<n>,"failed":<n>,"atomic_core_version":"<version>"}
+//
+// where <dir> is the name of the crate directory in the vendor tree and <reason> is one of
+// the kebab-case `SkipReason` variants. Events of different crates may interleave.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    CrateStarted {
+        #[serde(rename = "crate")]
+        krate: String,
+        manifest: PathBuf,
+    },
+    CratePatched {
+        #[serde(rename = "crate")]
+        krate: String,
+        manifest: PathBuf,
+    },
+    CrateSkipped {
+        #[serde(rename = "crate")]
+        krate: String,
+        manifest: PathBuf,
+        reason: SkipReason,
+    },
+    CrateFailed {
+        #[serde(rename = "crate")]
+        krate: String,
+        manifest: PathBuf,
+        error: String,
+    },
+    Done {
+        patched: usize,
+        skipped: usize,
+        failed: usize,
+        atomic_core_version: Option<String>,
+    },
+}
+
+// Serializes events sent from any thread to stdout, in the order they are received
+pub struct Events {
+    tx: Option<Sender<Event>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Events {
+    pub fn new(enabled: bool) -> Self {
+        if !enabled {
+            return Events {
+                tx: None,
+                writer: None,
+            };
+        }
+        let (tx, rx) = mpsc::channel::<Event>();
+        let writer = std::thread::spawn(move || {
+            let mut stdout = std::io::stdout();
+            for event in rx {
+                let line = serde_json::to_string(&event).unwrap();
+                // Nobody is listening anymore, keep draining the channel
+                let _ = writeln!(stdout, "{line}").and_then(|()| stdout.flush());
+            }
+        });
+        Events {
+            tx: Some(tx),
+            writer: Some(writer),
+        }
+    }
+
+    pub fn emit(&self, event: Event) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(event);
+        }
+    }
+
+    // Wait for every event to be written
+    pub fn finish(mut self) {
+        drop(self.tx.take());
+        if let Some(writer) = self.writer.take() {
+            writer.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::{collections::HashMap, path::Path};
+use tracing::info;
+
+use crate::{
+    audit,
+    metadata::{Metadata, Package},
+    package_id,
+    report::PatchReport,
+};
+
+// Log a one line rationale for the outcome of every crate in the report. With the resolved
+// graph at hand, patched crates also tell whether they are no_std.
+pub fn explain(report: &PatchReport, metadata: Option<&Metadata>) {
+    let packages = metadata
+        .map(|m| {
+            m.packages
+                .iter()
+                .map(|p| ((p.name.as_str(), p.version.as_str()), p))
+                .collect::<HashMap<_, _>>()
+        })
+        .unwrap_or_default();
+    let notes = |package: Option<&Package>| match package {
+        Some(package) if package.is_proc_macro() => " (proc-macro, built for the host)",
+        Some(package) => {
+            let features = metadata
+                .and_then(|m| m.node(&package.id))
+                .map(|n| n.features.as_slice())
+                .unwrap_or_default();
+            if audit::links_std(package, features) {
+                " (links std)"
+            } else {
+                " (no_std)"
+            }
+        }
+        None => "",
+    };
+    let line = |manifest: &Path, outcome: &str| {
+        let (krate, package) = match package_id(manifest) {
+            Ok((name, version)) => {
+                let package = packages.get(&(name.as_str(), version.as_str())).copied();
+                (format!("{name} {version}"), package)
+            }
+            Err(_) => (manifest.display().to_string(), None),
+        };
+        info!("{krate}: {outcome}{}", notes(package));
+    };
+
+    for manifest in &report.patched {
+        line(manifest, "patched");
+    }
+    for skipped in &report.skipped {
+        line(
+            &skipped.manifest,
+            &format!("skipped, {}", skipped.reason.explain()),
+        );
+    }
+    for failed in &report.failed {
+        let error = failed.error.lines().next().unwrap_or_default();
+        line(&failed.manifest, &format!("failed, {error}"));
+    }
+}
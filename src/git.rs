@@ -0,0 +1,50 @@
+use anyhow::Result;
+use std::{
+    collections::HashSet,
+    path::{Component, Path},
+    process::Command,
+};
+use tracing::warn;
+
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Names of the crate directories in `vendor_dir` with files changed since `since`, including
+// untracked ones. None if the directory is not in a git work tree, in which case every crate
+// counts as changed.
+pub fn changed_crates(vendor_dir: &Path, since: &str) -> Result<Option<HashSet<String>>> {
+    if git(vendor_dir, &["rev-parse", "--is-inside-work-tree"]).is_err() {
+        warn!(
+            "{} is not in a git repository, ignoring --since",
+            vendor_dir.display()
+        );
+        return Ok(None);
+    }
+    let changed = git(
+        vendor_dir,
+        &["diff", "--name-only", "--relative", since, "--", "."],
+    )?;
+    let untracked = git(
+        vendor_dir,
+        &["ls-files", "--others", "--exclude-standard", "--", "."],
+    )?;
+    Ok(Some(
+        changed
+            .lines()
+            .chain(untracked.lines())
+            .filter_map(|path| match Path::new(path).components().next()? {
+                Component::Normal(krate) => Some(krate.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect(),
+    ))
+}
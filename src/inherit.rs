@@ -0,0 +1,306 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, Item, TableLike};
+
+use crate::path_deps::{dependency_tables_mut, relative_path};
+
+// Package fields holding a path, relative to the manifest they are in
+const PATH_FIELDS: &[&str] = &["readme", "license-file"];
+
+// Replace what a manifest inherits from its workspace (`version.workspace = true`,
+// `serde = { workspace = true }`, `lints.workspace = true`) with the values of the workspace,
+// so that it still resolves once a stub cuts it off from the workspace. `manifest` is the
+// parsed manifest at `manifest_path`, which is left for the caller to write back.
+// Returns whether the manifest inherited anything.
+pub fn materialize(manifest_path: &Path, manifest: &mut DocumentMut) -> Result<bool> {
+    if !inherits(manifest) {
+        return Ok(false);
+    }
+    let dir = manifest_path.parent().unwrap();
+    let root_path = workspace_root(manifest_path, manifest)?.with_context(|| {
+        format!(
+            "{} inherits from its workspace, which cannot be found",
+            manifest_path.display()
+        )
+    })?;
+    let root: DocumentMut = std::fs::read_to_string(&root_path)?.parse()?;
+    let root_dir = root_path.parent().unwrap();
+    let workspace = root.get("workspace").context("not a workspace")?;
+    let missing = |what: String| {
+        anyhow::anyhow!(
+            "{} inherits {what}, which the workspace {} does not set",
+            manifest_path.display(),
+            root_path.display()
+        )
+    };
+
+    if let Some(package) = manifest
+        .get_mut("package")
+        .and_then(|p| p.as_table_like_mut())
+    {
+        for (key, item) in package.iter_mut() {
+            if !is_inherited(item) {
+                continue;
+            }
+            let mut value = workspace
+                .get("package")
+                .and_then(|p| p.get(key.get()))
+                .ok_or_else(|| missing(format!("package.{key}")))?
+                .clone();
+            if PATH_FIELDS.contains(&key.get()) {
+                rebase(&mut value, root_dir, dir);
+            }
+            *item = value;
+        }
+    }
+
+    let root_deps = workspace
+        .get("dependencies")
+        .and_then(|d| d.as_table_like());
+    for table in dependency_tables_mut(manifest) {
+        for (name, dep) in table.iter_mut() {
+            if !is_inherited(dep) {
+                continue;
+            }
+            let inherited = root_deps
+                .and_then(|deps| deps.get(name.get()))
+                .ok_or_else(|| missing(format!("dependency {name}")))?;
+            let dep = dep.as_table_like_mut().unwrap();
+            dep.remove("workspace");
+            match inherited.as_table_like() {
+                Some(inherited) => {
+                    for (key, value) in inherited.iter() {
+                        let mut value = value.clone();
+                        if key == "path" {
+                            rebase(&mut value, root_dir, dir);
+                        }
+                        merge(dep, key, value);
+                    }
+                }
+                // A bare version requirement
+                None => merge(dep, "version", inherited.clone()),
+            }
+            dep.fmt();
+        }
+    }
+
+    if manifest.get("lints").is_some_and(is_inherited) {
+        let lints = workspace
+            .get("lints")
+            .ok_or_else(|| missing("lints".into()))?;
+        manifest["lints"] = lints.clone();
+    }
+    Ok(true)
+}
+
+fn is_inherited(item: &Item) -> bool {
+    item.as_table_like()
+        .and_then(|t| t.get("workspace"))
+        .and_then(|w| w.as_bool())
+        == Some(true)
+}
+
+fn inherits(manifest: &mut DocumentMut) -> bool {
+    let package = manifest
+        .get("package")
+        .and_then(|p| p.as_table_like())
+        .is_some_and(|p| p.iter().any(|(_, item)| is_inherited(item)));
+    let lints = manifest.get("lints").is_some_and(is_inherited);
+    package
+        || lints
+        || dependency_tables_mut(manifest)
+            .iter()
+            .any(|table| table.iter().any(|(_, dep)| is_inherited(dep)))
+}
+
+// The manifest of the workspace a member belongs to: the one `package.workspace` points at,
+// or else the closest enclosing manifest with a [workspace] section
+fn workspace_root(manifest_path: &Path, manifest: &DocumentMut) -> Result<Option<PathBuf>> {
+    let dir = manifest_path.parent().unwrap();
+    if let Some(root) = manifest
+        .get("package")
+        .and_then(|p| p.get("workspace"))
+        .and_then(|w| w.as_str())
+    {
+        return Ok(Some(dir.join(root).join("Cargo.toml")));
+    }
+    for dir in dir.ancestors().skip(1) {
+        let path = dir.join("Cargo.toml");
+        if !path.is_file() {
+            continue;
+        }
+        let root: toml::Table = std::fs::read_to_string(&path)?.parse()?;
+        if root.contains_key("workspace") {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+// Members can only add features to the inherited dependency and make it optional, anything
+// else they set is kept as well
+fn merge(dep: &mut dyn TableLike, key: &str, value: Item) {
+    match (dep.get_mut(key), key) {
+        (Some(features), "features") => {
+            if let (Some(features), Some(inherited)) = (features.as_array_mut(), value.as_array()) {
+                let own = features.iter().cloned().collect::<Vec<_>>();
+                features.clear();
+                for feature in inherited.iter().chain(&own) {
+                    if !features.iter().any(|f| f.as_str() == feature.as_str()) {
+                        features.push(feature.clone());
+                    }
+                }
+            }
+        }
+        (Some(_), _) => {}
+        (None, _) => {
+            dep.insert(key, value);
+        }
+    }
+}
+
+// Make a path relative to the workspace root relative to the member instead
+fn rebase(value: &mut Item, root_dir: &Path, member_dir: &Path) {
+    if let Some(path) = value.as_str() {
+        let path = relative_path(member_dir, &root_dir.join(path));
+        *value = toml_edit::value(path.to_string_lossy().as_ref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const WORKSPACE: &str = r#"[workspace]
+members = ["crates/foo"]
+
+[workspace.package]
+version = "1.2.3"
+edition = "2021"
+readme = "README.md"
+
+[workspace.dependencies]
+serde = { version = "1", features = ["derive"], default-features = false }
+util = { path = "crates/util" }
+log = "0.4"
+
+[workspace.lints.rust]
+unsafe_code = "forbid"
+"#;
+
+    const MEMBER: &str = r#"[package]
+name = "foo"
+version.workspace = true
+edition.workspace = true
+readme.workspace = true
+
+[dependencies]
+serde = { workspace = true, features = ["std", "derive"] }
+util = { workspace = true, optional = true }
+log.workspace = true
+
+[lints]
+workspace = true
+"#;
+
+    fn parse(path: &Path) -> toml::Table {
+        std::fs::read_to_string(path).unwrap().parse().unwrap()
+    }
+
+    // Materialize the manifest at `path` and write it back
+    fn materialize_file(path: &Path) -> Result<bool> {
+        let mut manifest: DocumentMut = std::fs::read_to_string(path)?.parse()?;
+        let materialized = materialize(path, &mut manifest)?;
+        std::fs::write(path, manifest.to_string())?;
+        Ok(materialized)
+    }
+
+    #[test]
+    fn inherited_values_are_copied() {
+        let dir = TempDir::new();
+        dir.write("ws/Cargo.toml", WORKSPACE);
+        let member = dir.write("ws/crates/foo/Cargo.toml", MEMBER);
+        assert!(materialize_file(&member).unwrap());
+
+        let manifest = parse(&member);
+        let package = &manifest["package"];
+        assert_eq!(package["name"].as_str(), Some("foo"));
+        assert_eq!(package["version"].as_str(), Some("1.2.3"));
+        assert_eq!(package["edition"].as_str(), Some("2021"));
+        assert_eq!(package["readme"].as_str(), Some("../../README.md"));
+
+        let deps = &manifest["dependencies"];
+        let serde = &deps["serde"];
+        assert_eq!(serde.get("workspace"), None);
+        assert_eq!(serde["version"].as_str(), Some("1"));
+        assert_eq!(serde["default-features"].as_bool(), Some(false));
+        let features = serde["features"].as_array().unwrap();
+        assert_eq!(
+            features
+                .iter()
+                .map(|f| f.as_str().unwrap())
+                .collect::<Vec<_>>(),
+            ["derive", "std"]
+        );
+        assert_eq!(deps["util"]["path"].as_str(), Some("../util"));
+        assert_eq!(deps["util"]["optional"].as_bool(), Some(true));
+        assert_eq!(deps["log"]["version"].as_str(), Some("0.4"));
+        assert_eq!(
+            manifest["lints"]["rust"]["unsafe_code"].as_str(),
+            Some("forbid")
+        );
+
+        // Nothing is left to inherit
+        let materialized = std::fs::read_to_string(&member).unwrap();
+        assert!(!materialize_file(&member).unwrap());
+        assert_eq!(std::fs::read_to_string(&member).unwrap(), materialized);
+    }
+
+    #[test]
+    fn explicit_workspace_path() {
+        let dir = TempDir::new();
+        dir.write("ws/Cargo.toml", WORKSPACE);
+        let member = dir.write(
+            "elsewhere/foo/Cargo.toml",
+            "[package]\nname = \"foo\"\nworkspace = \"../../ws\"\nversion.workspace = true\nreadme.workspace = true\n",
+        );
+        assert!(materialize_file(&member).unwrap());
+        let manifest = parse(&member);
+        assert_eq!(manifest["package"]["version"].as_str(), Some("1.2.3"));
+        assert_eq!(
+            manifest["package"]["readme"].as_str(),
+            Some("../../ws/README.md")
+        );
+    }
+
+    #[test]
+    fn nothing_inherited() {
+        let dir = TempDir::new();
+        let contents = "[package]\nname = \"foo\"\nversion = \"1.0.0\"\n";
+        let manifest = dir.write("foo/Cargo.toml", contents);
+        assert!(!materialize_file(&manifest).unwrap());
+        assert_eq!(dir.read("foo/Cargo.toml"), contents);
+    }
+
+    #[test]
+    fn missing_workspace() {
+        let dir = TempDir::new();
+        let member = dir.write("crates/foo/Cargo.toml", MEMBER);
+        let error = materialize_file(&member).unwrap_err().to_string();
+        assert!(error.contains("which cannot be found"), "{error}");
+        assert_eq!(dir.read("crates/foo/Cargo.toml"), MEMBER);
+    }
+
+    #[test]
+    fn value_missing_from_the_workspace() {
+        let dir = TempDir::new();
+        dir.write("ws/Cargo.toml", WORKSPACE);
+        let contents =
+            "[package]\nname = \"foo\"\nversion.workspace = true\ndescription.workspace = true\n";
+        let member = dir.write("ws/crates/foo/Cargo.toml", contents);
+        let error = materialize_file(&member).unwrap_err().to_string();
+        assert!(error.contains("inherits package.description"), "{error}");
+        assert_eq!(dir.read("ws/crates/foo/Cargo.toml"), contents);
+    }
+}
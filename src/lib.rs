@@ -0,0 +1,73 @@
+//! The parts of cargo-atomic-patch other tools may want to build on
+
+use std::collections::HashSet;
+
+pub mod checksum;
+pub mod manifest;
+pub mod skip;
+#[cfg(test)]
+mod test_util;
+
+/// The replacement and the crates it depends on, which are never patched: patching them would
+/// make them depend on themselves
+pub const NO_PATCH: &[&str] = &["atomic-core", "critical-section", "portable-atomic"];
+
+/// The crates not to patch: [`NO_PATCH`] and `user_extra`, with every name normalized by
+/// [`normalize_crate_name`]
+pub fn effective_no_patch(user_extra: &[String]) -> HashSet<String> {
+    NO_PATCH
+        .iter()
+        .copied()
+        .chain(user_extra.iter().map(String::as_str))
+        .map(normalize_crate_name)
+        .collect()
+}
+
+/// A crate name in the form used to compare names: cargo and crates.io consider names equal
+/// regardless of case and of `-` or `_`
+pub fn normalize_crate_name(name: &str) -> String {
+    name.trim().to_lowercase().replace('_', "-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_names() {
+        assert_eq!(normalize_crate_name("atomic-core"), "atomic-core");
+        assert_eq!(normalize_crate_name("Atomic_Core"), "atomic-core");
+        assert_eq!(
+            normalize_crate_name(" portable_atomic\n"),
+            "portable-atomic"
+        );
+    }
+
+    #[test]
+    fn no_patch_merges_user_crates() {
+        let defaults = effective_no_patch(&[]);
+        assert_eq!(defaults.len(), NO_PATCH.len());
+        for name in NO_PATCH {
+            assert!(defaults.contains(*name));
+        }
+
+        let no_patch = effective_no_patch(&[
+            "My_Atomics".into(),
+            "critical_section".into(),
+            "spin".into(),
+        ]);
+        assert_eq!(
+            no_patch,
+            HashSet::from(
+                [
+                    "atomic-core",
+                    "critical-section",
+                    "portable-atomic",
+                    "my-atomics",
+                    "spin"
+                ]
+                .map(String::from)
+            )
+        );
+    }
+}
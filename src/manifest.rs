@@ -0,0 +1,105 @@
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, Value};
+
+/// What deciding whether to patch a vendored crate takes from its manifest, all read from a
+/// single parse of the file
+pub struct ManifestInfo {
+    /// Whether there is a [package] section at all, workspace-only manifests have none
+    pub has_package: bool,
+    /// The package name, which cannot be inherited and so is also set when the version is
+    pub name: Option<String>,
+    /// (name, version), if the package sets both (an inherited version is not resolved)
+    pub id: Option<(String, String)>,
+    /// Native library declared with `links`
+    pub links: Option<String>,
+    /// The build script: the one set with `build`, or build.rs next to the manifest unless
+    /// disabled with `build = false`
+    pub build_script: Option<PathBuf>,
+}
+
+impl ManifestInfo {
+    /// None if the manifest cannot be read or is not valid TOML
+    pub fn read(path: &Path) -> Option<Self> {
+        let contents = std::fs::read_to_string(path).ok()?;
+        Self::parse(&contents, path.parent()?)
+    }
+
+    /// `dir` is the directory of the manifest, where a default build script would be
+    pub fn parse(contents: &str, dir: &Path) -> Option<Self> {
+        Some(Self::from_document(&contents.parse().ok()?, dir))
+    }
+
+    /// From an already parsed manifest, for callers that need the document for more than this
+    pub fn from_document(manifest: &DocumentMut, dir: &Path) -> Self {
+        let Some(package) = manifest.get("package").and_then(|p| p.as_table_like()) else {
+            return ManifestInfo {
+                has_package: false,
+                name: None,
+                id: None,
+                links: None,
+                build_script: None,
+            };
+        };
+        let field = |key| package.get(key).and_then(|v| v.as_str()).map(String::from);
+        ManifestInfo {
+            has_package: true,
+            name: field("name"),
+            id: field("name").zip(field("version")),
+            links: field("links"),
+            build_script: match package.get("build").and_then(|b| b.as_value()) {
+                Some(Value::String(path)) => Some(dir.join(path.value())),
+                Some(Value::Boolean(build)) if !*build.value() => None,
+                // `build = true` is the default build script
+                _ => Some(dir.join("build.rs")).filter(|path| path.is_file()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const PACKAGE: &str = "[package]\nname = \"foo\"\nversion = \"1.0.0\"\n";
+
+    fn build_script(dir: &TempDir, build: &str) -> Option<PathBuf> {
+        let manifest = dir.write("Cargo.toml", &format!("{PACKAGE}{build}\n"));
+        ManifestInfo::read(&manifest).unwrap().build_script
+    }
+
+    #[test]
+    fn build_scripts() {
+        let dir = TempDir::new();
+        assert_eq!(build_script(&dir, ""), None);
+        assert_eq!(build_script(&dir, "build = true"), None);
+        assert_eq!(
+            build_script(&dir, "build = \"src/gen.rs\""),
+            Some(dir.path().join("src/gen.rs"))
+        );
+
+        let default = dir.write("build.rs", "fn main() {}\n");
+        assert_eq!(build_script(&dir, ""), Some(default.clone()));
+        assert_eq!(build_script(&dir, "build = true"), Some(default));
+        assert_eq!(build_script(&dir, "build = false"), None);
+    }
+
+    #[test]
+    fn package_fields() {
+        let dir = Path::new("/vendor/foo");
+        let info = ManifestInfo::parse(&format!("{PACKAGE}links = \"foo\"\n"), dir).unwrap();
+        assert!(info.has_package);
+        assert_eq!(info.id, Some(("foo".into(), "1.0.0".into())));
+        assert_eq!(info.links.as_deref(), Some("foo"));
+
+        let inherited = "[package]\nname = \"foo\"\nversion.workspace = true\n";
+        let info = ManifestInfo::parse(inherited, dir).unwrap();
+        assert!(info.has_package);
+        assert_eq!(info.name.as_deref(), Some("foo"));
+        assert_eq!(info.id, None);
+
+        let info = ManifestInfo::parse("[workspace]\nmembers = [\"foo\"]\n", dir).unwrap();
+        assert!(!info.has_package);
+        assert!(ManifestInfo::parse("[package", dir).is_none());
+    }
+}
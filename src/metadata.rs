@@ -0,0 +1,246 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{cargo, cli::FeatureArgs};
+
+// The subset of `cargo metadata --format-version 1` we care about
+#[derive(Deserialize)]
+pub struct Metadata {
+    pub packages: Vec<Package>,
+    pub resolve: Option<Resolve>,
+}
+
+#[derive(Deserialize)]
+pub struct Package {
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    // None for path dependencies and workspace members
+    pub source: Option<String>,
+    pub manifest_path: PathBuf,
+    // Native library declared with `links`
+    pub links: Option<String>,
+    pub targets: Vec<Target>,
+}
+
+#[derive(Deserialize)]
+pub struct Target {
+    pub kind: Vec<String>,
+    pub src_path: PathBuf,
+}
+
+#[derive(Deserialize)]
+pub struct Resolve {
+    pub root: Option<String>,
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Deserialize)]
+pub struct Node {
+    pub id: String,
+    pub dependencies: Vec<String>,
+    pub deps: Vec<NodeDep>,
+    pub features: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NodeDep {
+    pub pkg: String,
+    pub dep_kinds: Vec<DepKind>,
+}
+
+#[derive(Deserialize)]
+pub struct DepKind {
+    // None for normal dependencies, "dev" or "build" otherwise
+    pub kind: Option<String>,
+}
+
+impl Package {
+    // The library target, if any
+    pub fn lib(&self) -> Option<&Target> {
+        self.targets.iter().find(|t| {
+            t.kind
+                .iter()
+                .any(|k| matches!(k.as_str(), "lib" | "rlib" | "dylib" | "proc-macro"))
+        })
+    }
+
+    pub fn is_proc_macro(&self) -> bool {
+        self.lib()
+            .is_some_and(|t| t.kind.iter().any(|k| k == "proc-macro"))
+    }
+}
+
+impl NodeDep {
+    // Whether the dependency is linked into the dependent (rather than used by a build
+    // script or only by tests)
+    pub fn is_normal(&self) -> bool {
+        self.dep_kinds.iter().any(|k| k.kind.is_none())
+    }
+}
+
+impl Metadata {
+    // Resolve the graph for the given feature selection and, if any, target platform
+    pub fn load(
+        manifest_path: &Path,
+        features: &FeatureArgs,
+        target: Option<&str>,
+    ) -> Result<Self> {
+        let output = Self::command(manifest_path, features, target).output()?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "cargo metadata failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+
+    fn command(manifest_path: &Path, features: &FeatureArgs, target: Option<&str>) -> Command {
+        let mut cmd = cargo::captured("metadata");
+        cmd.args(["--format-version", "1"])
+            .arg("--manifest-path")
+            .arg(manifest_path)
+            .args(features.to_args());
+        if let Some(target) = target {
+            cmd.args(["--filter-platform", target]);
+        }
+        cmd
+    }
+
+    // (name, version) of every package that is part of the resolved dependency graph
+    pub fn resolved_packages(&self) -> HashSet<(String, String)> {
+        let Some(resolve) = &self.resolve else {
+            return HashSet::new();
+        };
+        let ids = resolve
+            .nodes
+            .iter()
+            .map(|n| n.id.as_str())
+            .collect::<HashSet<_>>();
+        self.packages
+            .iter()
+            .filter(|p| ids.contains(p.id.as_str()))
+            .map(|p| (p.name.clone(), p.version.clone()))
+            .collect()
+    }
+
+    // (name, version) of the named packages and of everything reachable from them in the
+    // resolved dependency graph
+    pub fn subtree(&self, names: &[String]) -> Result<HashSet<(String, String)>> {
+        let Some(resolve) = &self.resolve else {
+            return Ok(HashSet::new());
+        };
+        let packages = self
+            .packages
+            .iter()
+            .map(|p| (p.id.as_str(), p))
+            .collect::<HashMap<_, _>>();
+        let nodes = resolve
+            .nodes
+            .iter()
+            .map(|n| (n.id.as_str(), n))
+            .collect::<HashMap<_, _>>();
+
+        let mut queue = Vec::new();
+        for name in names {
+            let len = queue.len();
+            queue.extend(
+                nodes
+                    .keys()
+                    .filter(|id| packages.get(*id).is_some_and(|p| &p.name == name)),
+            );
+            if queue.len() == len {
+                anyhow::bail!("{name} is not part of the dependency graph");
+            }
+        }
+
+        let mut visited = HashSet::new();
+        while let Some(id) = queue.pop() {
+            if visited.insert(id) {
+                queue.extend(nodes[id].dependencies.iter().map(String::as_str));
+            }
+        }
+        Ok(visited
+            .into_iter()
+            .filter_map(|id| packages.get(id))
+            .map(|p| (p.name.clone(), p.version.clone()))
+            .collect())
+    }
+
+    pub fn package(&self, id: &str) -> Option<&Package> {
+        self.packages.iter().find(|p| p.id == id)
+    }
+
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        self.resolve.as_ref()?.nodes.iter().find(|n| n.id == id)
+    }
+
+    // Version the package named `name` was resolved to
+    pub fn resolved_version(&self, name: &str) -> Option<String> {
+        self.resolved_packages()
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, version)| version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_forwards_features_and_target() {
+        let features = FeatureArgs {
+            features: vec!["a".into(), "b".into()],
+            all_features: false,
+            no_default_features: true,
+        };
+        let cmd = Metadata::command(
+            Path::new("/p/Cargo.toml"),
+            &features,
+            Some("thumbv6m-none-eabi"),
+        );
+        let args = cmd.get_args().collect::<Vec<_>>();
+        assert_eq!(
+            args,
+            [
+                "metadata",
+                "--color",
+                "never",
+                "--format-version",
+                "1",
+                "--manifest-path",
+                "/p/Cargo.toml",
+                "--features",
+                "a,b",
+                "--no-default-features",
+                "--filter-platform",
+                "thumbv6m-none-eabi",
+            ]
+        );
+    }
+
+    #[test]
+    fn command_without_selection() {
+        let cmd = Metadata::command(Path::new("Cargo.toml"), &FeatureArgs::default(), None);
+        let args = cmd.get_args().collect::<Vec<_>>();
+        assert_eq!(
+            args,
+            [
+                "metadata",
+                "--color",
+                "never",
+                "--format-version",
+                "1",
+                "--manifest-path",
+                "Cargo.toml"
+            ]
+        );
+    }
+}
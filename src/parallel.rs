@@ -0,0 +1,34 @@
+use rayon::iter::{IntoParallelRefIterator, ParallelBridge, ParallelIterator};
+
+// The loops over vendored crates run on the rayon thread pool, or one item at a time on the
+// current thread with --no-parallel. Both give the same results in the same order.
+
+pub fn map<T: Sync, R: Send>(
+    serial: bool,
+    items: &[T],
+    f: impl Fn(&T) -> R + Sync + Send,
+) -> Vec<R> {
+    if serial {
+        items.iter().map(f).collect()
+    } else {
+        items.par_iter().map(f).collect()
+    }
+}
+
+// Unlike `map`, the order of the results follows the scheduling when running in parallel
+pub fn filter_map<I, R>(
+    serial: bool,
+    iter: I,
+    f: impl Fn(I::Item) -> Option<R> + Sync + Send,
+) -> Vec<R>
+where
+    I: Iterator + Send,
+    I::Item: Send,
+    R: Send,
+{
+    if serial {
+        iter.filter_map(f).collect()
+    } else {
+        iter.par_bridge().filter_map(f).collect()
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::Result;
+use std::path::{Component, Path, PathBuf};
+use toml_edit::{DocumentMut, Item, TableLike};
+
+use crate::package_id;
+
+const TABLES: &[&str] = &["dependencies", "dev-dependencies", "build-dependencies"];
+
+// A dependency given by path in the manifest of a vendored crate
+pub struct PathDep {
+    // Key in the dependency table
+    pub dependency: String,
+    pub path: String,
+    // Whether there is a manifest at `path`
+    pub resolves: bool,
+}
+
+// Path dependencies of a manifest in `dir`, in its dependency tables and the target specific
+// ones
+pub fn find(manifest: &DocumentMut, dir: &Path) -> Vec<PathDep> {
+    let mut deps = Vec::new();
+    for (dependency, dep) in dependencies(manifest) {
+        if let Some(path) = dep.get("path").and_then(|p| p.as_str()) {
+            deps.push(PathDep {
+                dependency: dependency.to_string(),
+                path: path.to_string(),
+                resolves: dir.join(path).join("Cargo.toml").is_file(),
+            });
+        }
+    }
+    deps
+}
+
+// Point the path dependencies of a manifest that do not resolve at the crate of the same
+// name in the vendor directory, if there is exactly one.
+// Returns the dependencies rewritten, with their new path.
+pub fn rewrite(manifest_path: &Path, vendor_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut manifest: DocumentMut = std::fs::read_to_string(manifest_path)?.parse()?;
+    let dir = manifest_path.parent().unwrap();
+    let mut rewritten = Vec::new();
+    for table in dependency_tables_mut(&mut manifest) {
+        for (dependency, dep) in table.iter_mut() {
+            let Some(dep) = dep.as_table_like_mut() else {
+                continue;
+            };
+            let Some(path) = dep.get("path").and_then(|p| p.as_str()) else {
+                continue;
+            };
+            if dir.join(path).join("Cargo.toml").is_file() {
+                continue;
+            }
+            let name = dep
+                .get("package")
+                .and_then(|p| p.as_str())
+                .unwrap_or(dependency.get());
+            let version = dep.get("version").and_then(|v| v.as_str());
+            let Some(target) = vendored(vendor_dir, name, version)? else {
+                continue;
+            };
+            let relative = relative_path(dir, &target);
+            dep.insert(
+                "path",
+                toml_edit::value(relative.to_string_lossy().as_ref()),
+            );
+            rewritten.push((dependency.to_string(), relative));
+        }
+    }
+    if !rewritten.is_empty() {
+        std::fs::write(manifest_path, manifest.to_string())?;
+    }
+    Ok(rewritten)
+}
+
+fn dependencies(manifest: &DocumentMut) -> impl Iterator<Item = (&str, &dyn TableLike)> {
+    let targets = manifest
+        .get("target")
+        .and_then(|t| t.as_table_like())
+        .into_iter()
+        .flat_map(|targets| targets.iter())
+        .filter_map(|(_, target)| target.as_table_like());
+    std::iter::once(manifest.as_table() as &dyn TableLike)
+        .chain(targets)
+        .flat_map(|parent| TABLES.iter().filter_map(|table| parent.get(table)))
+        .filter_map(Item::as_table_like)
+        .flat_map(|table| table.iter())
+        .filter_map(|(name, dep)| Some((name, dep.as_table_like()?)))
+}
+
+pub fn dependency_tables_mut(manifest: &mut DocumentMut) -> Vec<&mut dyn TableLike> {
+    let mut tables = Vec::new();
+    for (key, item) in manifest.as_table_mut().iter_mut() {
+        if TABLES.contains(&key.get()) {
+            tables.extend(item.as_table_like_mut());
+        } else if key.get() == "target" {
+            let targets = item
+                .as_table_like_mut()
+                .into_iter()
+                .flat_map(|t| t.iter_mut());
+            for (_, target) in targets {
+                let target = target
+                    .as_table_like_mut()
+                    .into_iter()
+                    .flat_map(|t| t.iter_mut());
+                for (key, item) in target {
+                    if TABLES.contains(&key.get()) {
+                        tables.extend(item.as_table_like_mut());
+                    }
+                }
+            }
+        }
+    }
+    tables
+}
+
+// The directory of the only vendored crate named `name` matching the version requirement,
+// if any
+fn vendored(vendor_dir: &Path, name: &str, version: Option<&str>) -> Result<Option<PathBuf>> {
+    let req = version
+        .map(semver::VersionReq::parse)
+        .transpose()?
+        .unwrap_or(semver::VersionReq::STAR);
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(vendor_dir)? {
+        let dir = entry?.path();
+        let Ok((package, version)) = package_id(&dir.join("Cargo.toml")) else {
+            continue;
+        };
+        if package == name && semver::Version::parse(&version).is_ok_and(|v| req.matches(&v)) {
+            found.push(dir);
+        }
+    }
+    Ok(match found.as_slice() {
+        [dir] => Some(dir.clone()),
+        _ => None,
+    })
+}
+
+// `to` relative to `from`, both being absolute
+pub fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    std::iter::repeat_n(Component::ParentDir, from.len() - common)
+        .chain(to[common..].iter().copied())
+        .collect()
+}
@@ -0,0 +1,240 @@
+use anyhow::Result;
+use cargo_atomic_patch::{effective_no_patch, normalize_crate_name, skip::SkipReason};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    add_crate,
+    cli::{FeatureArgs, Options},
+    dependency_state,
+    metadata::Metadata,
+    package_id, package_skip_reason, path_patches, refuse_own_package, selection, DependencyState,
+    Replacements,
+};
+
+// Bumped on any incompatible change to the format below
+pub const PLAN_VERSION: u32 = 1;
+
+// The actions a run would take, as written by `cargo atomic-patch plan`:
+//
+// {
+//   "version": 1,
+//   "manifest": "/path/to/project/Cargo.toml",
+//   "replacement": {
+//     "name": "atomic-core", "rename": "core", "source": "crates-io", "features": [...],
+//     "default_features": true,
+//     "crate_features": { "heapless": ["critical-section"] }
+//   },
+//   "root": { "action": "patch" },
+//   "crates": [
+//     { "name": "ryu", "version": "1.0.23", "action": "patch" },
+//     { "name": "libz-sys", "version": "1.1.22", "links": "z", "action": "patch" },
+//     { "name": "portable-atomic", "version": "1.15.0", "action": "skip", "reason": "no-patch" }
+//   ]
+// }
+//
+// `crates` lists every dependency cargo vendor will vendor, sorted by name and version, plus
+// the crates overridden with a path in a [patch] section of the manifest. Those are patched
+// where they are, and have their `manifest` set.
+// `crate_features` (only present if not empty) overrides the features for some crates.
+// `default_features` defaults to true when missing.
+// `reason` is one of the kebab-case `SkipReason` variants. `links` is only present for crates
+// declaring a native library.
+#[derive(Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    pub manifest: PathBuf,
+    pub replacement: Replacements,
+    pub root: Action,
+    pub crates: Vec<PlannedCrate>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlannedCrate {
+    pub name: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub links: Option<String>,
+    // Only set for the crates patched in place rather than vendored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<PathBuf>,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Action {
+    Patch,
+    Skip { reason: SkipReason },
+}
+
+impl Plan {
+    pub fn load(path: &Path) -> Result<Self> {
+        let plan: Plan = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        if plan.version != PLAN_VERSION {
+            anyhow::bail!(
+                "unsupported plan version {} (expected {PLAN_VERSION})",
+                plan.version
+            );
+        }
+        Ok(plan)
+    }
+
+    // (name, version) of the crates to patch
+    pub fn to_patch(&self) -> HashSet<(String, String)> {
+        self.crates
+            .iter()
+            .filter(|c| c.action == Action::Patch)
+            .map(|c| (c.name.clone(), c.version.clone()))
+            .collect()
+    }
+
+    // Make sure the vendored crates are the ones the plan was made for
+    pub fn check_vendored(&self, vendor_dir: &Path) -> Result<()> {
+        let planned = self
+            .crates
+            .iter()
+            .filter(|c| c.manifest.is_none())
+            .map(|c| (c.name.clone(), c.version.clone()))
+            .collect::<HashSet<_>>();
+        let vendored = vendored_packages(vendor_dir)?;
+        let fmt = |ids: HashSet<&(String, String)>| {
+            let mut ids = ids
+                .into_iter()
+                .map(|(name, version)| format!("{name} {version}"))
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids.join(", ")
+        };
+        // The replacement and its dependencies only enter the graph once the root is patched
+        let no_patch = effective_no_patch(&[]);
+        let unplanned = vendored
+            .difference(&planned)
+            .filter(|(name, _)| !no_patch.contains(&normalize_crate_name(name)))
+            .collect::<HashSet<_>>();
+        if !unplanned.is_empty() {
+            anyhow::bail!("vendored crates not in the plan: {}", fmt(unplanned));
+        }
+        let missing = planned.difference(&vendored).collect::<HashSet<_>>();
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "planned crates missing from the vendor directory: {}",
+                fmt(missing)
+            );
+        }
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        let describe = |action: &Action| match action {
+            Action::Patch => "patch".to_string(),
+            Action::Skip { reason } => format!("skip ({reason})"),
+        };
+        println!("{}: {}", self.manifest.display(), describe(&self.root));
+        for krate in &self.crates {
+            println!(
+                "{} {}: {}",
+                krate.name,
+                krate.version,
+                describe(&krate.action)
+            );
+        }
+    }
+}
+
+// (name, version) of the crates at the top level of a vendor directory
+fn vendored_packages(vendor_dir: &Path) -> Result<HashSet<(String, String)>> {
+    WalkDir::new(vendor_dir)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name() == "Cargo.toml")
+        .map(|e| package_id(e.path()))
+        .collect()
+}
+
+// Work out what patching the project would do without touching it.
+// Vendored crates are the same as the ones in the registry cache, so the decision of whether
+// they are already patched is taken on the latter.
+pub fn plan(manifest_path: &Path, opts: &Options, replacements: &Replacements) -> Result<Plan> {
+    let root = match dependency_state(manifest_path, &replacements.default)? {
+        DependencyState::UpToDate => Action::Skip {
+            reason: SkipReason::AlreadyPatched,
+        },
+        _ => {
+            // Make sure the root manifest will take the replacement before planning anything else
+            if let Ok((name, _)) = package_id(manifest_path) {
+                refuse_own_package(manifest_path, &name, &replacements.default)?;
+            }
+            add_crate(manifest_path, &replacements.default, false, true)?;
+            Action::Patch
+        }
+    };
+    let metadata = Metadata::load(manifest_path, &opts.features, opts.target.as_deref())?;
+    let mut replacements = replacements.clone();
+    replacements.pin(&metadata);
+    let selected = selection(&metadata, opts)?;
+    // cargo vendor vendors everything in the lockfile, whatever the features or platform
+    let all_features = FeatureArgs {
+        all_features: true,
+        ..Default::default()
+    };
+    let vendored = Metadata::load(manifest_path, &all_features, None)?;
+    let patches = path_patches(manifest_path)?;
+    let no_patch = effective_no_patch(&opts.no_patch);
+
+    let mut crates = vendored
+        .packages
+        .iter()
+        .filter_map(|p| {
+            // Path dependencies are not vendored, and the only ones patched are [patch] overrides
+            match p.source {
+                Some(_) => Some((p, None)),
+                None => {
+                    let manifest = p.manifest_path.canonicalize().ok()?;
+                    patches.contains(&manifest).then_some((p, Some(manifest)))
+                }
+            }
+        })
+        .map(|(p, manifest)| {
+            let id = (p.name.clone(), p.version.clone());
+            let reason = match package_skip_reason(
+                &id,
+                p.links.as_deref(),
+                opts,
+                &no_patch,
+                selected.as_ref(),
+            ) {
+                Some(reason) => Some(reason),
+                None => (dependency_state(&p.manifest_path, &replacements.for_crate(&p.name))?
+                    == DependencyState::UpToDate)
+                    .then_some(SkipReason::AlreadyPatched),
+            };
+            Ok(PlannedCrate {
+                name: id.0,
+                version: id.1,
+                links: p.links.clone(),
+                manifest,
+                action: match reason {
+                    Some(reason) => Action::Skip { reason },
+                    None => Action::Patch,
+                },
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    crates.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+
+    Ok(Plan {
+        version: PLAN_VERSION,
+        manifest: manifest_path.to_path_buf(),
+        replacement: replacements,
+        root,
+        crates,
+    })
+}
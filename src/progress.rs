@@ -0,0 +1,233 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::info;
+
+use crate::Replacements;
+
+pub const PROGRESS_FILE: &str = ".atomic-patch-progress";
+
+// One line of the progress file, in the order they are written
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Entry {
+    Start { fingerprint: String },
+    // Vendoring is done, with the config cargo vendor printed
+    Vendored { config: String },
+    // The manifest carries the replacement
+    Done { manifest: PathBuf },
+}
+
+// Progress of a --resume run, recorded as it goes so that an interrupted run can pick up where
+// it stopped. What a previous run recorded is only used if it was made with the same
+// command line and replacement, otherwise the file is started over.
+pub struct Progress {
+    path: PathBuf,
+    vendor_config: Option<String>,
+    done: HashSet<PathBuf>,
+    file: Mutex<File>,
+}
+
+impl Progress {
+    pub fn open(path: &Path, replacements: &Replacements) -> Result<Self> {
+        let fingerprint = fingerprint(replacements)?;
+        // The last line may have been cut short by the interruption
+        let contents = std::fs::read_to_string(path).unwrap_or_default();
+        let entries = contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect::<Vec<Entry>>();
+        let resumed = matches!(
+            entries.first(),
+            Some(Entry::Start { fingerprint: f }) if *f == fingerprint
+        );
+
+        let mut vendor_config = None;
+        let mut done = HashSet::new();
+        let file = if resumed {
+            for entry in entries {
+                match entry {
+                    Entry::Start { .. } => {}
+                    Entry::Vendored { config } => vendor_config = Some(config),
+                    Entry::Done { manifest } => {
+                        done.insert(manifest);
+                    }
+                }
+            }
+            info!(
+                "Resuming the run recorded in {}, {} crates already done",
+                path.display(),
+                done.len()
+            );
+            let mut file = OpenOptions::new().append(true).open(path)?;
+            // Start the next entry on a line of its own, past what was cut short
+            if !contents.ends_with('\n') {
+                file.write_all(b"\n")?;
+            }
+            file
+        } else {
+            if !entries.is_empty() {
+                info!(
+                    "{} was recorded with other options, starting over",
+                    path.display()
+                );
+            }
+            File::create(path)?
+        };
+        let progress = Progress {
+            path: path.to_path_buf(),
+            vendor_config,
+            done,
+            file: Mutex::new(file),
+        };
+        if !resumed {
+            progress.write(&Entry::Start { fingerprint })?;
+        }
+        Ok(progress)
+    }
+
+    fn write(&self, entry: &Entry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())
+            .and_then(|()| file.flush())
+            .with_context(|| format!("cannot write {}", self.path.display()))
+    }
+
+    // The config cargo vendor printed, if the recorded run got past vendoring
+    pub fn vendor_config(&self) -> Option<&str> {
+        self.vendor_config.as_deref()
+    }
+
+    pub fn vendored(&self, config: &str) -> Result<()> {
+        self.write(&Entry::Vendored {
+            config: config.to_string(),
+        })
+    }
+
+    pub fn is_done(&self, manifest: &Path) -> bool {
+        self.done.contains(manifest)
+    }
+
+    pub fn done(&self, manifest: &Path) -> Result<()> {
+        self.write(&Entry::Done {
+            manifest: manifest.to_path_buf(),
+        })
+    }
+
+    // Remove the file once the run completed
+    pub fn finish(self) -> Result<()> {
+        drop(self.file);
+        std::fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+// Every option can change the outcome, so the command line is hashed as a whole (minus
+// --resume itself), together with the replacement it resolved to
+fn fingerprint(replacements: &Replacements) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for arg in std::env::args_os().skip(1).filter(|arg| arg != "--resume") {
+        hasher.update(arg.as_encoded_bytes());
+        hasher.update([0]);
+    }
+    hasher.update(serde_json::to_vec(replacements)?);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::TempDir, Crate, Source};
+    use std::collections::BTreeMap;
+
+    fn replacements(version: &str) -> Replacements {
+        Replacements {
+            default: Crate {
+                name: "atomic-core".into(),
+                version: Some(version.into()),
+                rename: Some("core".into()),
+                source: Source::CratesIo,
+                features: vec!["critical-section".into()],
+                default_features: true,
+            },
+            nested_version: None,
+            pin_nested: false,
+            crate_features: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn resume() {
+        let dir = TempDir::new();
+        let path = dir.path().join(PROGRESS_FILE);
+        let progress = Progress::open(&path, &replacements("0.1")).unwrap();
+        assert_eq!(progress.vendor_config(), None);
+        progress.vendored("[source]\n").unwrap();
+        progress.done(Path::new("/vendor/a/Cargo.toml")).unwrap();
+        progress.done(Path::new("/vendor/b/Cargo.toml")).unwrap();
+        // Interrupted
+        drop(progress);
+
+        let progress = Progress::open(&path, &replacements("0.1")).unwrap();
+        assert_eq!(progress.vendor_config(), Some("[source]\n"));
+        assert!(progress.is_done(Path::new("/vendor/a/Cargo.toml")));
+        assert!(progress.is_done(Path::new("/vendor/b/Cargo.toml")));
+        assert!(!progress.is_done(Path::new("/vendor/c/Cargo.toml")));
+        progress.finish().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn truncated_last_line() {
+        let dir = TempDir::new();
+        let path = dir.path().join(PROGRESS_FILE);
+        let progress = Progress::open(&path, &replacements("0.1")).unwrap();
+        progress.done(Path::new("/vendor/a/Cargo.toml")).unwrap();
+        drop(progress);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"done":{"manifest":"/vendor/b/Carg"#)
+            .unwrap();
+        drop(file);
+
+        let progress = Progress::open(&path, &replacements("0.1")).unwrap();
+        assert!(progress.is_done(Path::new("/vendor/a/Cargo.toml")));
+        assert!(!progress.is_done(Path::new("/vendor/b/Cargo.toml")));
+        assert!(!progress.is_done(Path::new("/vendor/b/Carg")));
+        // What comes after the cut is recorded in full
+        progress.done(Path::new("/vendor/b/Cargo.toml")).unwrap();
+        drop(progress);
+        let progress = Progress::open(&path, &replacements("0.1")).unwrap();
+        assert!(progress.is_done(Path::new("/vendor/a/Cargo.toml")));
+        assert!(progress.is_done(Path::new("/vendor/b/Cargo.toml")));
+    }
+
+    #[test]
+    fn other_options_start_over() {
+        let dir = TempDir::new();
+        let path = dir.path().join(PROGRESS_FILE);
+        let progress = Progress::open(&path, &replacements("0.1")).unwrap();
+        progress.vendored("[source]\n").unwrap();
+        progress.done(Path::new("/vendor/a/Cargo.toml")).unwrap();
+        drop(progress);
+
+        let progress = Progress::open(&path, &replacements("0.2")).unwrap();
+        assert_eq!(progress.vendor_config(), None);
+        assert!(!progress.is_done(Path::new("/vendor/a/Cargo.toml")));
+        drop(progress);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        // Nor is a file that does not start with a fingerprint resumed
+        std::fs::write(&path, "garbage\n").unwrap();
+        let progress = Progress::open(&path, &replacements("0.2")).unwrap();
+        assert!(!progress.is_done(Path::new("/vendor/a/Cargo.toml")));
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::Result;
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+};
+use toml_edit::DocumentMut;
+use walkdir::WalkDir;
+
+// Targets that are not needed to build a dependency, with the directory cargo looks for them
+// in when the manifest does not give a path
+const TARGETS: &[(&str, &str)] = &[
+    ("test", "tests"),
+    ("example", "examples"),
+    ("bench", "benches"),
+];
+
+// Remove the top level directories `dirs` from a vendored crate, together with the targets its
+// manifest declares in them.
+// Directories holding the library, a binary or the build script are kept.
+// Returns the files removed or modified, relative to the crate root.
+pub fn prune(crate_root: &Path, dirs: &[String]) -> Result<Vec<PathBuf>> {
+    let manifest_path = crate_root.join("Cargo.toml");
+    let mut manifest: DocumentMut = std::fs::read_to_string(&manifest_path)?.parse()?;
+
+    let needed = needed_dirs(&manifest);
+    let pruned = dirs
+        .iter()
+        .map(String::as_str)
+        .filter(|dir| !needed.contains(dir) && crate_root.join(dir).is_dir())
+        .collect::<HashSet<_>>();
+
+    let mut changed = Vec::new();
+    for dir in &pruned {
+        let dir = crate_root.join(dir);
+        changed.extend(
+            WalkDir::new(&dir)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .map(|e| e.path().strip_prefix(crate_root).unwrap().to_path_buf()),
+        );
+        std::fs::remove_dir_all(dir)?;
+    }
+
+    let mut manifest_changed = false;
+    for (kind, default_dir) in TARGETS {
+        let Some(targets) = manifest
+            .get_mut(kind)
+            .and_then(|t| t.as_array_of_tables_mut())
+        else {
+            continue;
+        };
+        let len = targets.len();
+        targets.retain(|target| {
+            let dir = match target.get("path").and_then(|p| p.as_str()) {
+                Some(path) => top_dir(path),
+                None => Some(*default_dir),
+            };
+            !dir.is_some_and(|dir| pruned.contains(dir))
+        });
+        manifest_changed |= targets.len() != len;
+    }
+    if manifest_changed {
+        std::fs::write(&manifest_path, manifest.to_string())?;
+        changed.push("Cargo.toml".into());
+    }
+    Ok(changed)
+}
+
+// Top level directories of the targets needed to build the crate
+fn needed_dirs(manifest: &DocumentMut) -> HashSet<&str> {
+    let lib = manifest
+        .get("lib")
+        .and_then(|l| l.get("path"))
+        .and_then(|p| p.as_str());
+    let bins = manifest
+        .get("bin")
+        .and_then(|b| b.as_array_of_tables())
+        .into_iter()
+        .flatten()
+        .filter_map(|b| b.get("path")?.as_str());
+    let build = manifest
+        .get("package")
+        .and_then(|p| p.get("build"))
+        .and_then(|b| b.as_str());
+    lib.into_iter()
+        .chain(bins)
+        .chain(build)
+        .filter_map(top_dir)
+        .collect()
+}
+
+// First directory of a path relative to the crate root, if it is in a directory at all
+fn top_dir(path: &str) -> Option<&str> {
+    let mut components = Path::new(path)
+        .components()
+        .filter(|c| !matches!(c, Component::CurDir));
+    let first = components.next()?;
+    components.next()?;
+    first.as_os_str().to_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn targets_outside_the_build_are_pruned() {
+        let dir = TempDir::new();
+        dir.write(
+            "Cargo.toml",
+            r#"[package]
+name = "fixture"
+version = "0.1.0"
+build = "build/main.rs"
+
+[lib]
+path = "lib/lib.rs"
+
+[[bin]]
+name = "tool"
+path = "./tools/tool.rs"
+
+[[test]]
+name = "smoke"
+path = "checks/smoke.rs"
+
+[[test]]
+name = "unit"
+
+[[bench]]
+name = "speed"
+"#,
+        );
+        for file in [
+            "build/main.rs",
+            "lib/lib.rs",
+            "tools/tool.rs",
+            "checks/smoke.rs",
+            "tests/unit.rs",
+            "benches/speed.rs",
+            "examples/demo.rs",
+            "examples/nested/data.txt",
+        ] {
+            dir.write(file, "");
+        }
+        // A directory holding a needed target is kept even when asked for
+        let dirs = [
+            "checks", "tests", "benches", "examples", "lib", "tools", "build", "docs",
+        ]
+        .map(String::from);
+        let mut changed = prune(dir.path(), &dirs).unwrap();
+        changed.sort();
+        assert_eq!(
+            changed,
+            [
+                "Cargo.toml",
+                "benches/speed.rs",
+                "checks/smoke.rs",
+                "examples/demo.rs",
+                "examples/nested/data.txt",
+                "tests/unit.rs",
+            ]
+            .map(PathBuf::from)
+        );
+        for kept in ["build", "lib", "tools"] {
+            assert!(dir.path().join(kept).is_dir(), "{kept} was removed");
+        }
+        for removed in ["checks", "tests", "benches", "examples"] {
+            assert!(!dir.path().join(removed).exists(), "{removed} was kept");
+        }
+        let manifest: DocumentMut = dir.read("Cargo.toml").parse().unwrap();
+        // The emptied [[test]] and [[bench]] arrays are not written back at all
+        assert!(manifest.get("test").is_none());
+        assert!(manifest.get("bench").is_none());
+        assert_eq!(manifest["bin"][0]["name"].as_str(), Some("tool"));
+    }
+
+    #[test]
+    fn nothing_to_prune() {
+        let dir = TempDir::new();
+        dir.write(
+            "Cargo.toml",
+            "[package]\nname = \"fixture\"\nversion = \"0.1.0\"\n",
+        );
+        dir.write("src/lib.rs", "");
+        let changed = prune(dir.path(), &["tests".to_string()]).unwrap();
+        assert!(changed.is_empty());
+        assert!(dir.path().join("src/lib.rs").is_file());
+    }
+
+    #[test]
+    fn top_dirs() {
+        assert_eq!(top_dir("src/lib.rs"), Some("src"));
+        assert_eq!(top_dir("./build/main.rs"), Some("build"));
+        assert_eq!(top_dir("build.rs"), None);
+        assert_eq!(top_dir("./build.rs"), None);
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::Result;
+
+use crate::cargo;
+
+// Path of a crate in the registry index, see
+// https://doc.rust-lang.org/cargo/reference/registry-index.html#index-files
+fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{name}"),
+        2 => format!("2/{name}"),
+        3 => format!("3/{}/{name}", &name[..1]),
+        _ => format!("{}/{}/{name}", &name[..2], &name[2..4]),
+    }
+}
+
+// Whether a published version has been yanked, according to the copy of the registry index
+// cargo keeps in CARGO_HOME. cargo refreshes it whenever it resolves the project online, as it
+// just did to find the version, and offline it is all there is to go by.
+pub fn is_yanked(name: &str, version: &str) -> Result<bool> {
+    let index = cargo::home()?.join("registry").join("index");
+    for registry in std::fs::read_dir(&index)?.filter_map(|e| e.ok()) {
+        let path = registry.path().join(".cache").join(index_path(name));
+        let Ok(cache) = std::fs::read(&path) else {
+            continue;
+        };
+        if let Some(yanked) = find_yanked(&cache, version) {
+            return Ok(yanked);
+        }
+    }
+    anyhow::bail!(
+        "{name} {version} not found in cargo's copy of the registry index in {}",
+        index.display()
+    )
+}
+
+// The cache file of a crate is a small header followed by NUL-separated pairs of version and
+// index entry. Only the JSON entries parse, so the rest is skipped.
+fn find_yanked(cache: &[u8], version: &str) -> Option<bool> {
+    cache
+        .split(|b| *b == 0)
+        .filter_map(|chunk| serde_json::from_slice::<serde_json::Value>(chunk).ok())
+        .find(|entry| entry["vers"] == version)
+        .map(|entry| entry["yanked"].as_bool().unwrap_or(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_paths() {
+        assert_eq!(index_path("a"), "1/a");
+        assert_eq!(index_path("ab"), "2/ab");
+        assert_eq!(index_path("abc"), "3/a/abc");
+        assert_eq!(index_path("abcd"), "ab/cd/abcd");
+        assert_eq!(index_path("atomic-core"), "at/om/atomic-core");
+        assert_eq!(index_path("Atomic_Core"), "at/om/atomic_core");
+    }
+
+    #[test]
+    fn yanked_versions_in_the_cache() {
+        let entry = |vers: &str, yanked: bool| {
+            format!(
+                r#"{vers}{}{{"name":"atomic-core","vers":"{vers}","yanked":{yanked}}}{}"#,
+                '\0', '\0'
+            )
+        };
+        let mut cache = b"\x03\x02\0\0\0etag\0".to_vec();
+        cache.extend(entry("0.1.0", true).bytes());
+        cache.extend(entry("0.2.0", false).bytes());
+        assert_eq!(find_yanked(&cache, "0.1.0"), Some(true));
+        assert_eq!(find_yanked(&cache, "0.2.0"), Some(false));
+        assert_eq!(find_yanked(&cache, "0.3.0"), None);
+    }
+}
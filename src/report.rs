@@ -0,0 +1,299 @@
+use anyhow::Result;
+use cargo_atomic_patch::skip::SkipReason;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use tracing::{error, info};
+
+use crate::{crate_name, package_id, Crate, Replacements, Source};
+
+// Outcome of a run, printed as a summary at the end
+#[derive(Default, Serialize)]
+pub struct PatchReport {
+    // Version of the replacement crate the project resolved to, if known
+    pub atomic_core_version: Option<String>,
+    pub patched: Vec<PathBuf>,
+    pub skipped: Vec<Skipped>,
+    pub failed: Vec<Failed>,
+    // Crates declaring a native library with `links`, patched or not. They often need
+    // manual attention on embedded targets.
+    pub links: Vec<Links>,
+    // Crates with a build script, patched or not. Build scripts run on the host and keep
+    // using the real core, which is usually fine but can be surprising.
+    pub build_scripts: Vec<BuildScript>,
+    // Dependencies of vendored crates given by path, which break when the crate they point
+    // to is not where the path says
+    pub path_deps: Vec<PathDependency>,
+    // See `PatchReport::tree_hash`
+    pub tree_hash: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Skipped {
+    pub manifest: PathBuf,
+    pub reason: SkipReason,
+}
+
+#[derive(Serialize)]
+pub struct Links {
+    pub manifest: PathBuf,
+    pub links: String,
+}
+
+#[derive(Serialize)]
+pub struct BuildScript {
+    pub manifest: PathBuf,
+    pub script: PathBuf,
+}
+
+#[derive(Serialize)]
+pub struct PathDependency {
+    pub manifest: PathBuf,
+    pub dependency: String,
+    pub path: String,
+    // Whether there is a manifest at `path`, before any rewrite
+    pub resolves: bool,
+    // New path set with --rewrite-path-deps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rewritten: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+pub struct Failed {
+    pub manifest: PathBuf,
+    pub error: String,
+}
+
+// Record of what a run applied, to compare the patched dependency set between runs
+#[derive(Serialize)]
+struct Provenance<'a> {
+    atomic_core: Replacement<'a>,
+    crates: Vec<PatchedCrate>,
+}
+
+#[derive(Serialize)]
+struct Replacement<'a> {
+    #[serde(flatten)]
+    spec: &'a Crate,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolved_version: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct PatchedCrate {
+    name: String,
+    version: String,
+    // The spec this crate got, which differs from the one of the project with per-crate
+    // features or a nested version
+    atomic_core: Crate,
+}
+
+impl PatchReport {
+    pub fn print_summary(&self) {
+        info!(
+            "Patched {} crates ({} skipped, {} failed) using atomic-core {}",
+            self.patched.len(),
+            self.skipped.len(),
+            self.failed.len(),
+            self.atomic_core_version
+                .as_deref()
+                .unwrap_or("(unknown version)")
+        );
+        if let Some(hash) = &self.tree_hash {
+            info!("Patched tree hash: {hash}");
+        }
+    }
+
+    // Log one error per distinct cause, listing the crates that failed with it. The path of
+    // each crate is left out of its message so that the same failure in different crates
+    // ends up in the same group. Each error is logged in full at debug level as it happens.
+    // `root` gives the root of the crate a manifest belongs to.
+    pub fn log_failures(&self, root: impl Fn(&Path) -> PathBuf) {
+        let mut groups = BTreeMap::<_, Vec<_>>::new();
+        for failed in &self.failed {
+            let root = root(&failed.manifest);
+            let error = failed.error.replace(&*root.to_string_lossy(), "<crate>");
+            groups.entry(error).or_default().push(crate_name(&root));
+        }
+        for (error, crates) in groups {
+            match crates.as_slice() {
+                [krate] => error!("{krate} failed: {error}"),
+                crates => error!(
+                    "{} crates failed ({}): {error}",
+                    crates.len(),
+                    crates.join(", ")
+                ),
+            }
+        }
+    }
+
+    // Manifests patched by this run or an earlier one
+    fn carrying_replacement(&self) -> impl Iterator<Item = &PathBuf> {
+        self.patched.iter().chain(
+            self.skipped
+                .iter()
+                .filter(|s| s.reason == SkipReason::AlreadyPatched)
+                .map(|s| &s.manifest),
+        )
+    }
+
+    // Write every crate carrying the replacement (patched by this run or an earlier one) to
+    // `path`, as TOML if the file has a .toml extension and JSON otherwise
+    pub fn write_provenance(&self, path: &Path, replacements: &Replacements) -> Result<()> {
+        let mut crates = self
+            .carrying_replacement()
+            .map(|manifest| {
+                let (name, version) = package_id(manifest)?;
+                let atomic_core = replacements.for_crate(&name).into_owned();
+                Ok(PatchedCrate {
+                    name,
+                    version,
+                    atomic_core,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        crates.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+        let provenance = Provenance {
+            atomic_core: Replacement {
+                spec: &replacements.default,
+                resolved_version: self.atomic_core_version.as_deref(),
+            },
+            crates,
+        };
+        let contents = if path.extension().is_some_and(|e| e == "toml") {
+            toml::to_string(&provenance)?
+        } else {
+            serde_json::to_string_pretty(&provenance)?
+        };
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    // A hash of the replacement spec and of the manifest of every crate carrying the
+    // replacement, to tell whether two runs patched the tree the same way. Crates are
+    // identified by name and version rather than path, so the hash does not depend on where
+    // the project is. The rest of the sources is left out: cargo checks it anyway.
+    pub fn tree_hash(&self, replacements: &Replacements) -> Result<String> {
+        let mut crates = self
+            .carrying_replacement()
+            .map(|manifest| {
+                let (name, version) = package_id(manifest)?;
+                let contents = std::fs::read(manifest)?;
+                Ok(format!(
+                    "{name} {version} {}",
+                    hex(&Sha256::digest(contents))
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        crates.sort();
+        let mut hasher = Sha256::new();
+        hasher.update(serde_json::to_vec(&location_independent(replacements))?);
+        for krate in crates {
+            hasher.update(b"\n");
+            hasher.update(krate);
+        }
+        Ok(hex(&hasher.finalize()))
+    }
+}
+
+// The spec without the location of a local checkout of the replacement, which depends on
+// where the project is
+fn location_independent(replacements: &Replacements) -> Replacements {
+    let mut replacements = replacements.clone();
+    if let Source::Path(path) = &mut replacements.default.source {
+        *path = PathBuf::new();
+    }
+    replacements
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    // Manifest of a crate at `path` in the directory
+    fn manifest(dir: &TempDir, path: &str, name: &str) -> PathBuf {
+        dir.write(
+            &format!("{path}/Cargo.toml"),
+            &format!("[package]\nname = \"{name}\"\nversion = \"1.0.0\"\n"),
+        )
+    }
+
+    fn replacements() -> Replacements {
+        Replacements {
+            default: Crate {
+                name: "atomic-core".into(),
+                version: Some("0.2".into()),
+                rename: Some("core".into()),
+                source: Source::CratesIo,
+                features: vec!["critical-section".into()],
+                default_features: true,
+            },
+            nested_version: Some("=0.2.3".into()),
+            pin_nested: false,
+            crate_features: [("spin".to_string(), vec![])].into(),
+        }
+    }
+
+    #[test]
+    fn tree_hash_does_not_depend_on_where_the_project_is() {
+        let hash = |project: &str| {
+            let dir = TempDir::new();
+            let report = PatchReport {
+                patched: vec![manifest(&dir, &format!("{project}/vendor/spin"), "spin")],
+                ..Default::default()
+            };
+            let mut replacements = replacements();
+            replacements.default.source = Source::Path(dir.path().join(project).join("core"));
+            report.tree_hash(&replacements).unwrap()
+        };
+        assert_eq!(hash("a"), hash("b"));
+    }
+
+    #[test]
+    fn provenance_records_the_spec_of_each_crate() {
+        let dir = TempDir::new();
+        let report = PatchReport {
+            atomic_core_version: Some("0.2.3".into()),
+            patched: vec![manifest(&dir, "vendor/spin", "spin")],
+            skipped: vec![Skipped {
+                manifest: manifest(&dir, "vendor/ryu", "ryu"),
+                reason: SkipReason::AlreadyPatched,
+            }],
+            ..Default::default()
+        };
+        let replacements = replacements();
+        let path = dir.path().join("provenance.json");
+        report.write_provenance(&path, &replacements).unwrap();
+        let provenance: serde_json::Value =
+            serde_json::from_str(&dir.read("provenance.json")).unwrap();
+        assert_eq!(provenance["atomic_core"]["version"], "0.2");
+        assert_eq!(provenance["atomic_core"]["resolved_version"], "0.2.3");
+        let crates = provenance["crates"].as_array().unwrap();
+        assert_eq!(crates[0]["name"], "ryu");
+        assert_eq!(crates[0]["atomic_core"]["version"], "=0.2.3");
+        assert_eq!(crates[0]["atomic_core"]["features"][0], "critical-section");
+        assert_eq!(crates[1]["name"], "spin");
+        assert_eq!(crates[1]["atomic_core"]["version"], "=0.2.3");
+        assert!(crates[1]["atomic_core"]["features"]
+            .as_array()
+            .unwrap()
+            .is_empty());
+
+        // TOML needs the spec of each crate after its plain fields
+        let path = dir.path().join("provenance.toml");
+        report.write_provenance(&path, &replacements).unwrap();
+        let provenance: toml::Table = dir.read("provenance.toml").parse().unwrap();
+        assert_eq!(
+            provenance["crates"][1]["atomic_core"]["version"].as_str(),
+            Some("=0.2.3")
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Why a crate was left alone. It serializes (and displays) as the kebab-case name of the
+/// variant, e.g. `already-patched`, which is what reports, plans and events use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    /// The replacement itself or one of its dependencies, patching it would create a cycle
+    NoPatch,
+    /// Excluded with --exclude
+    Excluded,
+    /// Not part of the graph selected by the features, target, --only or
+    /// --atomic-feature-gated
+    NotSelected,
+    /// Already depends on the replacement
+    AlreadyPatched,
+    /// Links a native library, skipped with --skip-links
+    Links,
+    /// Sources unchanged since the --since git ref
+    Unchanged,
+    /// The manifest has no [package] section, e.g. a workspace stub
+    NoPackage,
+}
+
+impl SkipReason {
+    /// The kebab-case name, as serialized
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SkipReason::NoPatch => "no-patch",
+            SkipReason::Excluded => "excluded",
+            SkipReason::NotSelected => "not-selected",
+            SkipReason::AlreadyPatched => "already-patched",
+            SkipReason::Links => "links",
+            SkipReason::Unchanged => "unchanged",
+            SkipReason::NoPackage => "no-package",
+        }
+    }
+
+    /// Why a crate with this reason is left alone, in a sentence fragment
+    pub fn explain(self) -> &'static str {
+        match self {
+            SkipReason::NoPatch => {
+                "the replacement, one of its dependencies or set with --no-patch"
+            }
+            SkipReason::Excluded => "excluded with --exclude",
+            SkipReason::NotSelected => {
+                "not built with the selected features, target, --only or --atomic-feature-gated"
+            }
+            SkipReason::AlreadyPatched => "already aliases core to the replacement",
+            SkipReason::Links => "links a native library and --skip-links is set",
+            SkipReason::Unchanged => "unchanged since the --since ref",
+            SkipReason::NoPackage => "the manifest has no [package] section",
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    const ALL: [SkipReason; 7] = [
+        SkipReason::NoPatch,
+        SkipReason::Excluded,
+        SkipReason::NotSelected,
+        SkipReason::AlreadyPatched,
+        SkipReason::Links,
+        SkipReason::Unchanged,
+        SkipReason::NoPackage,
+    ];
+
+    #[test]
+    fn explanations() {
+        let explanations = ALL.map(SkipReason::explain);
+        for explanation in explanations {
+            // A fragment that fits in `<crate> skipped: <explanation>`
+            assert!(!explanation.is_empty());
+            assert!(explanation.starts_with(|c: char| c.is_lowercase()));
+            assert!(!explanation.ends_with('.'));
+        }
+        assert_eq!(explanations.iter().collect::<HashSet<_>>().len(), ALL.len());
+        assert!(SkipReason::Links.explain().contains("--skip-links"));
+        assert!(SkipReason::Excluded.explain().contains("--exclude"));
+        assert!(SkipReason::Unchanged.explain().contains("--since"));
+    }
+
+    #[test]
+    fn serialization() {
+        let names = [
+            "no-patch",
+            "excluded",
+            "not-selected",
+            "already-patched",
+            "links",
+            "unchanged",
+            "no-package",
+        ];
+        for (reason, name) in ALL.into_iter().zip(names) {
+            let json = serde_json::to_string(&reason).unwrap();
+            assert_eq!(json, format!("\"{name}\""));
+            assert_eq!(serde_json::from_str::<SkipReason>(&json).unwrap(), reason);
+            // Human output and JSON use the same name
+            assert_eq!(reason.as_str(), name);
+            assert_eq!(reason.to_string(), name);
+        }
+        assert!(serde_json::from_str::<SkipReason>("\"NoPatch\"").is_err());
+    }
+}
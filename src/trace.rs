@@ -0,0 +1,187 @@
+use std::{fmt, io::IsTerminal};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+use crate::cli::{Color, Options};
+
+// Keeps the trace file open until the end of the run
+pub struct Guard {
+    #[cfg(feature = "chrome")]
+    _chrome: Option<tracing_chrome::FlushGuard>,
+}
+
+// Log to stderr, filtered by RUST_LOG (`info` by default, `debug` with --verbose), and
+// optionally record every span into a chrome trace (open it in chrome://tracing or
+// https://ui.perfetto.dev)
+pub fn init(opts: &Options) -> Guard {
+    // Each line is formatted on its own and written under the stderr lock, so that lines
+    // logged from different threads do not interleave
+    let fmt = tracing_subscriber::fmt::layer()
+        .event_format(CratePrefix)
+        .with_writer(|| std::io::stderr().lock())
+        .with_ansi(match opts.color {
+            Color::Always => true,
+            Color::Never => false,
+            Color::Auto => {
+                std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+        })
+        .with_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| if opts.verbose { "debug" } else { "info" }.into()),
+        );
+    let registry = tracing_subscriber::registry().with(RecordCrate).with(fmt);
+
+    #[cfg(feature = "chrome")]
+    {
+        let (chrome, guard) = match &opts.trace_chrome {
+            Some(file) => {
+                let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new()
+                    .file(file)
+                    .include_args(true)
+                    .build();
+                (Some(layer), Some(guard))
+            }
+            None => (None, None),
+        };
+        registry.with(chrome).init();
+        Guard { _chrome: guard }
+    }
+
+    #[cfg(not(feature = "chrome"))]
+    {
+        registry.init();
+        Guard {}
+    }
+}
+
+// Formats events as `LEVEL [crate] message`, the crate being the one recorded by the
+// innermost span the event is in, if any. The other spans are left out:
+// they mark the steps of a run and mostly matter in traces.
+struct CratePrefix;
+
+impl<S, N> FormatEvent<S, N> for CratePrefix
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let level = *event.metadata().level();
+        if writer.has_ansi_escapes() {
+            let color = match level {
+                Level::ERROR => 31,
+                Level::WARN => 33,
+                Level::INFO => 32,
+                Level::DEBUG => 34,
+                Level::TRACE => 35,
+            };
+            write!(writer, "\x1b[{color}m{level:>5}\x1b[0m ")?;
+        } else {
+            write!(writer, "{level:>5} ")?;
+        }
+
+        let krate = ctx.event_scope().and_then(|scope| {
+            scope
+                .into_iter()
+                .find_map(|span| Some(span.extensions().get::<Krate>()?.0.clone()))
+        });
+        if let Some(krate) = krate {
+            write!(writer, "[{krate}] ")?;
+        }
+
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+// The `krate` field of a span, kept in its extensions by `RecordCrate`
+struct Krate(String);
+
+struct RecordCrate;
+
+impl<S> Layer<S> for RecordCrate
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = KrateVisitor(None);
+        attrs.record(&mut visitor);
+        if let (Some(krate), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(Krate(krate));
+        }
+    }
+}
+
+struct KrateVisitor(Option<String>);
+
+impl Visit for KrateVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "krate" {
+            self.0 = Some(format!("{value:?}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+    use tracing::{info_span, warn};
+
+    // Collects the formatted output of a subscriber
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn events_are_prefixed_with_their_crate() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry().with(RecordCrate).with(
+            tracing_subscriber::fmt::layer()
+                .event_format(CratePrefix)
+                .with_writer(move || writer.clone())
+                .with_ansi(false),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            warn!("outside");
+            let _span = info_span!("patch_crate", krate = %"ryu").entered();
+            warn!("inside");
+            // Spans without a crate do not hide the one of their parent
+            let _step = info_span!("prune").entered();
+            warn!("nested");
+        });
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            output,
+            " WARN outside\n WARN [ryu] inside\n WARN [ryu] nested\n"
+        );
+    }
+}
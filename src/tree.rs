@@ -0,0 +1,115 @@
+use anyhow::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use crate::{
+    audit,
+    cli::Options,
+    metadata::Metadata,
+    plan::{self, Action},
+    Replacements,
+};
+
+// Print the dependency graph the build resolves to, like `cargo tree` does, with what patching
+// would do to each crate and whether it links std. Only normal dependencies are shown, as
+// they are the only ones linked into the target build.
+pub fn print(manifest_path: &Path, opts: &Options, replacements: &Replacements) -> Result<()> {
+    let plan = plan::plan(manifest_path, opts, replacements)?;
+    let metadata = Metadata::load(manifest_path, &opts.features, opts.target.as_deref())?;
+    let Some(root) = metadata.resolve.as_ref().and_then(|r| r.root.as_deref()) else {
+        anyhow::bail!("{} has no root package", manifest_path.display());
+    };
+    let actions = plan
+        .crates
+        .iter()
+        .map(|c| ((c.name.as_str(), c.version.as_str()), &c.action))
+        .collect::<HashMap<_, _>>();
+    let tree = Tree {
+        metadata: &metadata,
+        root,
+        root_action: &plan.root,
+        actions,
+    };
+    tree.print(root, "", &mut HashSet::new());
+    Ok(())
+}
+
+struct Tree<'a> {
+    metadata: &'a Metadata,
+    root: &'a str,
+    root_action: &'a Action,
+    actions: HashMap<(&'a str, &'a str), &'a Action>,
+}
+
+impl<'a> Tree<'a> {
+    // Print the dependencies of `id`, already printed on the current line. Crates already
+    // expanded elsewhere are marked with (*) instead, as cargo tree does.
+    fn print(&self, id: &'a str, prefix: &str, visited: &mut HashSet<&'a str>) {
+        let Some(node) = self.metadata.node(id) else {
+            return;
+        };
+        if id == self.root {
+            println!("{}", self.describe(id));
+        }
+        if !visited.insert(id) {
+            return;
+        }
+        let deps = node
+            .deps
+            .iter()
+            .filter(|d| d.is_normal())
+            .map(|d| d.pkg.as_str())
+            .collect::<Vec<_>>();
+        for (i, dep) in deps.iter().enumerate() {
+            let last = i + 1 == deps.len();
+            let seen = visited.contains(dep);
+            println!(
+                "{prefix}{}{}{}",
+                if last { "└── " } else { "├── " },
+                self.describe(dep),
+                if seen { " (*)" } else { "" }
+            );
+            if !seen {
+                let prefix = format!("{prefix}{}", if last { "    " } else { "│   " });
+                self.print(dep, &prefix, visited);
+            }
+        }
+    }
+
+    // `name vversion [action, std linkage]`
+    fn describe(&self, id: &str) -> String {
+        let Some(package) = self.metadata.package(id) else {
+            return id.to_string();
+        };
+        let action = if id == self.root {
+            Some(self.root_action)
+        } else {
+            self.actions
+                .get(&(package.name.as_str(), package.version.as_str()))
+                .copied()
+        };
+        let action = match action {
+            Some(Action::Patch) => "will patch".to_string(),
+            Some(Action::Skip { reason }) => format!("skip ({reason})"),
+            // Path dependencies other than [patch] overrides
+            None => "skip (not vendored)".to_string(),
+        };
+        let features = self
+            .metadata
+            .node(id)
+            .map(|n| n.features.as_slice())
+            .unwrap_or_default();
+        let std = if package.is_proc_macro() {
+            "proc-macro"
+        } else if package.lib().is_none() {
+            "no library"
+        } else if audit::links_std(package, features) {
+            "std"
+        } else {
+            "no_std"
+        };
+        format!("{} v{} [{action}, {std}]", package.name, package.version)
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::Result;
+use cargo_atomic_patch::checksum;
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+use tracing::{error, info};
+
+use crate::{
+    cli::{Format, Options},
+    crate_root, dependency_state, discover, package_id, parallel, root_manifest, selection,
+    DependencyState, Metadata, Replacements,
+};
+
+// Outcome of a check, printed as JSON with --format json or jsonl
+#[derive(Default, Serialize)]
+pub struct VerifyReport {
+    pub verified: Vec<PathBuf>,
+    pub inconsistent: Vec<Inconsistent>,
+}
+
+#[derive(Serialize)]
+pub struct Inconsistent {
+    // Manifest, or vendor directory if missing
+    pub path: PathBuf,
+    pub problem: String,
+}
+
+// Check the project in the current directory and its vendor directory, or the --in-place
+// directory on its own, failing if anything is not consistently patched
+pub fn run(opts: &Options, replacements: &Replacements) -> Result<()> {
+    let mut report = VerifyReport::default();
+    let mut replacements = replacements.clone();
+    let (dir, selected) = match &opts.in_place {
+        Some(dir) => (dir.canonicalize()?, None),
+        None => {
+            let manifest = root_manifest()?;
+            match dependency_state(&manifest, &replacements.default) {
+                Ok(DependencyState::UpToDate) => report.verified.push(manifest.clone()),
+                state => report.inconsistent.push(Inconsistent {
+                    path: manifest.clone(),
+                    problem: problem(state),
+                }),
+            }
+            let metadata = Metadata::load(&manifest, &opts.features, opts.target.as_deref())?;
+            replacements.pin(&metadata);
+            (
+                manifest.parent().unwrap().join("vendor"),
+                selection(&metadata, opts)?,
+            )
+        }
+    };
+    verify(&dir, opts, &replacements, selected.as_ref(), &mut report)?;
+
+    for inconsistent in &report.inconsistent {
+        error!("{}: {}", inconsistent.path.display(), inconsistent.problem);
+    }
+    match opts.format {
+        Format::Human => {}
+        Format::Json | Format::Jsonl => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    let total = report.verified.len() + report.inconsistent.len();
+    if !report.inconsistent.is_empty() {
+        anyhow::bail!(
+            "{} of {total} manifests are inconsistent",
+            report.inconsistent.len()
+        );
+    }
+    info!("{total} manifests verified");
+    Ok(())
+}
+
+fn problem(state: Result<DependencyState>) -> String {
+    match state {
+        Err(e) => format!("malformed manifest: {e}"),
+        Ok(DependencyState::Missing) => "not patched".into(),
+        Ok(DependencyState::Outdated) => "patched with a different replacement spec".into(),
+        Ok(DependencyState::UpToDate) => unreachable!(),
+    }
+}
+
+// Check that a vendor directory is still in the state patching it leaves it in: every crate
+// that would be patched depends on the replacement as specified and its checksums do not
+// cover the patched manifest. Malformed manifests, including ones with a duplicate
+// [workspace] table, are reported as well.
+fn verify(
+    vendor_dir: &Path,
+    opts: &Options,
+    replacements: &Replacements,
+    selected: Option<&HashSet<(String, String)>>,
+    report: &mut VerifyReport,
+) -> Result<()> {
+    if !vendor_dir.is_dir() {
+        report.inconsistent.push(Inconsistent {
+            path: vendor_dir.to_path_buf(),
+            problem: "no vendored sources".into(),
+        });
+        return Ok(());
+    }
+    let manifests = discover(vendor_dir, opts, selected)
+        .into_iter()
+        .filter(|discovered| discovered.reason.is_none())
+        .map(|discovered| discovered.manifest)
+        .collect::<Vec<_>>();
+    let problems = parallel::map(opts.no_parallel, &manifests, |manifest| {
+        let (name, _) = match package_id(manifest) {
+            Ok(id) => id,
+            Err(e) => return Some(format!("malformed manifest: {e}")),
+        };
+        match dependency_state(manifest, &replacements.for_crate(&name)) {
+            Ok(DependencyState::UpToDate) => {}
+            state => return Some(problem(state)),
+        }
+        let root = crate_root(vendor_dir, manifest);
+        let file = manifest.strip_prefix(&root).unwrap().to_path_buf();
+        match checksum::is_cleared(&root, &opts.checksum_file, opts.checksum_mode, &[file]) {
+            Ok(true) => None,
+            Ok(false) => Some(format!(
+                "{} still checks the patched files",
+                opts.checksum_file
+            )),
+            Err(e) => Some(format!("malformed {}: {e}", opts.checksum_file)),
+        }
+    });
+
+    for (manifest, problem) in manifests.into_iter().zip(problems) {
+        match problem {
+            Some(problem) => report.inconsistent.push(Inconsistent {
+                path: manifest,
+                problem,
+            }),
+            None => report.verified.push(manifest),
+        }
+    }
+    Ok(())
+}